@@ -0,0 +1,121 @@
+//! Writing attachments of cached messages to disk.
+
+use anyhow::{Context, Result};
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::mime::Attachment;
+
+/// Write attachments into `dir`, returning the paths written. Filenames are
+/// sanitized and de-duplicated against each other and existing files.
+pub fn download(dir: &Path, attachments: &[Attachment]) -> Result<Vec<PathBuf>> {
+    fs::create_dir_all(dir).with_context(|| format!("create directory {}", dir.display()))?;
+    let mut taken = HashSet::new();
+    let mut paths = Vec::new();
+    for (index, attachment) in attachments.iter().enumerate() {
+        let name = safe_filename(attachment.filename.as_deref(), &attachment.mime, index);
+        let path = unique_path(dir, &name, &mut taken);
+        fs::write(&path, attachment.contents)
+            .with_context(|| format!("write {}", path.display()))?;
+        paths.push(path);
+    }
+    Ok(paths)
+}
+
+/// Strip directories, control and reserved characters from an attachment
+/// name, falling back to `attachment-<n>.<subtype>` when nothing is left.
+pub fn safe_filename(name: Option<&str>, mime: &str, index: usize) -> String {
+    let base = name
+        .unwrap_or_default()
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or_default();
+    let cleaned: String = base
+        .chars()
+        .filter(|ch| !ch.is_control() && !matches!(ch, ':' | '*' | '?' | '"' | '<' | '>' | '|'))
+        .collect();
+    let cleaned = cleaned.trim().trim_start_matches('.').trim();
+    if !cleaned.is_empty() {
+        return cleaned.to_string();
+    }
+
+    let extension = mime
+        .split_once('/')
+        .map(|(_, subtype)| subtype)
+        .filter(|subtype| subtype.chars().all(|ch| ch.is_ascii_alphanumeric()))
+        .unwrap_or("bin");
+    format!("attachment-{}.{}", index + 1, extension)
+}
+
+/// Pick `name`, or `stem_1.ext`, `stem_2.ext`, ... if it is already taken.
+fn unique_path(dir: &Path, name: &str, taken: &mut HashSet<String>) -> PathBuf {
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, Some(extension)),
+        _ => (name, None),
+    };
+    let mut candidate = name.to_string();
+    let mut counter = 1;
+    while taken.contains(&candidate) || dir.join(&candidate).exists() {
+        candidate = match extension {
+            Some(extension) => format!("{stem}_{counter}.{extension}"),
+            None => format!("{stem}_{counter}"),
+        };
+        counter += 1;
+    }
+    taken.insert(candidate.clone());
+    dir.join(candidate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filenames_lose_their_directories() {
+        let name = |raw: &str| safe_filename(Some(raw), "application/pdf", 0);
+        assert_eq!(name("../../.ssh/authorized_keys"), "authorized_keys");
+        assert_eq!(name("..\\..\\Windows\\evil.exe"), "evil.exe");
+        assert_eq!(name("/etc/passwd"), "passwd");
+        assert_eq!(name(".bashrc"), "bashrc");
+        assert_eq!(name("re:port?\u{7}.pdf"), "report.pdf");
+        assert_eq!(name("report.pdf"), "report.pdf");
+        assert_eq!(name(".."), "attachment-1.pdf");
+        assert_eq!(
+            safe_filename(Some("dir/"), "image/png", 2),
+            "attachment-3.png"
+        );
+        assert_eq!(
+            safe_filename(None, "text/x-weird+type", 0),
+            "attachment-1.bin"
+        );
+    }
+
+    #[test]
+    fn taken_names_get_a_counter() {
+        let dir = std::env::temp_dir().join(format!(
+            "himalaya-cache-test-{}-attachments",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("report.pdf"), b"existing").unwrap();
+
+        let mut taken = HashSet::new();
+        assert_eq!(
+            unique_path(&dir, "report.pdf", &mut taken),
+            dir.join("report_1.pdf")
+        );
+        assert_eq!(
+            unique_path(&dir, "report.pdf", &mut taken),
+            dir.join("report_2.pdf")
+        );
+        assert_eq!(unique_path(&dir, "notes", &mut taken), dir.join("notes"));
+        assert_eq!(unique_path(&dir, "notes", &mut taken), dir.join("notes_1"));
+        assert_eq!(unique_path(&dir, ".env", &mut taken), dir.join(".env"));
+        assert_eq!(unique_path(&dir, ".env", &mut taken), dir.join(".env_1"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
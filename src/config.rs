@@ -0,0 +1,335 @@
+//! Settings read from `~/.config/himalaya-cache/config.toml` (or the file
+//! given with `--cache-config`), with per-account and per-folder overrides and
+//! environment variable overrides.
+
+use anyhow::{Context, Result};
+use directories::BaseDirs;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use crate::store::Compression;
+
+/// zstd level used when the config does not set one.
+pub const DEFAULT_COMPRESSION_LEVEL: i32 = 3;
+
+/// Environment variable naming the config file when `--cache-config` is absent.
+pub const CONFIG_ENV: &str = "HIMALAYA_CACHE_CONFIG";
+
+/// Environment variables overriding config keys, at every scope.
+pub const ENV_OVERRIDES: &[(&str, &str)] = &[
+    ("HIMALAYA_CACHE_DIR", "cache_dir"),
+    ("HIMALAYA_CACHE_THREADS", "threads"),
+    ("HIMALAYA_CACHE_COMPRESSION", "compression"),
+    ("HIMALAYA_CACHE_COMPRESSION_LEVEL", "compression_level"),
+    ("HIMALAYA_CACHE_RETRIES", "retries"),
+    ("HIMALAYA_CACHE_RETRY_DELAY_MS", "retry_delay_ms"),
+    ("HIMALAYA_CACHE_PAGE_SIZE", "page_size"),
+    ("HIMALAYA_CACHE_SYNC", "sync"),
+    ("HIMALAYA_CACHE_BODIES", "bodies"),
+    ("HIMALAYA_CACHE_MAX_BODIES", "max_bodies"),
+];
+
+/// Keys that only make sense for the whole cache, not one account or folder.
+const GLOBAL_KEYS: &[&str] = &[
+    "cache_dir",
+    "himalaya_bin",
+    "threads",
+    "compression",
+    "compression_level",
+];
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// Contents of `config.toml`. Every key is optional; `accounts.<name>` and
+/// `folders.<name>` tables take the same keys as overrides, and an account
+/// table may hold its own `folders`.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Cache root directory.
+    cache_dir: Option<PathBuf>,
+    /// Path of the himalaya binary, searched in `$PATH` when unset.
+    himalaya_bin: Option<PathBuf>,
+    /// Threads used to download messages; rayon picks when unset.
+    threads: Option<usize>,
+    /// Compression of newly cached message bodies: `none` or `zstd`.
+    compression: Option<CompressionKind>,
+    /// zstd level, from 1 (fastest) to 22 (smallest).
+    compression_level: Option<i32>,
+    /// Attempts made for each himalaya command.
+    retries: Option<u32>,
+    /// Pause between two attempts, in milliseconds.
+    retry_delay_ms: Option<u64>,
+    /// Page size of the envelope listings fetched during sync.
+    page_size: Option<u32>,
+    /// Whether a sync without `--account`/`--folder` visits it.
+    sync: Option<bool>,
+    /// Whether sync downloads message bodies or only envelopes.
+    bodies: Option<bool>,
+    /// Download the bodies of the newest messages of a folder only.
+    max_bodies: Option<usize>,
+    accounts: BTreeMap<String, Config>,
+    folders: BTreeMap<String, Config>,
+    #[serde(skip)]
+    path: PathBuf,
+    /// Environment and command line overrides, applied last.
+    #[serde(skip)]
+    overrides: Option<Box<Config>>,
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CompressionKind {
+    #[default]
+    None,
+    Zstd,
+}
+
+/// Effective settings of a scope, as printed by `config show`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Settings {
+    pub config: PathBuf,
+    pub cache_dir: PathBuf,
+    pub himalaya_bin: Option<PathBuf>,
+    pub threads: Option<usize>,
+    pub compression: CompressionKind,
+    pub compression_level: i32,
+    pub retries: u32,
+    pub retry_delay_ms: u64,
+    pub page_size: u32,
+    pub sync: bool,
+    pub bodies: bool,
+    pub max_bodies: Option<usize>,
+}
+
+/// Load the config of this process. `path` comes from `--cache-config` and
+/// `cache_dir` from `--cache-dir`, which wins over every other setting.
+pub fn init(path: Option<PathBuf>, cache_dir: Option<PathBuf>) -> Result<()> {
+    let mut config = Config::load(path)?;
+    if let Some(overrides) = &mut config.overrides
+        && cache_dir.is_some()
+    {
+        overrides.cache_dir = cache_dir;
+    }
+    let _ = CONFIG.set(config);
+    Ok(())
+}
+
+/// The config loaded by [`init`], or the defaults before it ran.
+pub fn get() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+}
+
+impl Config {
+    /// Load a config file, or the defaults when it does not exist.
+    fn load(path: Option<PathBuf>) -> Result<Self> {
+        let path = match path.or_else(|| std::env::var_os(CONFIG_ENV).map(PathBuf::from)) {
+            Some(path) => {
+                if !path.exists() {
+                    anyhow::bail!("config file {} does not exist", path.display());
+                }
+                path
+            }
+            None => default_path()?,
+        };
+        let mut config = if path.exists() {
+            let data =
+                fs::read_to_string(&path).with_context(|| format!("read {}", path.display()))?;
+            let config: Config =
+                toml::from_str(&data).with_context(|| format!("parse {}", path.display()))?;
+            config
+                .check_scopes()
+                .with_context(|| format!("parse {}", path.display()))?;
+            config
+        } else {
+            Config::default()
+        };
+        config.path = path;
+        config.overrides = Some(Box::new(env_overrides()?));
+        Ok(config)
+    }
+
+    /// Reject keys set at a scope they do not apply to.
+    fn check_scopes(&self) -> Result<()> {
+        for (name, account) in &self.accounts {
+            account
+                .check_nested(false)
+                .with_context(|| format!("in accounts.{name}"))?;
+            for (folder, config) in &account.folders {
+                config
+                    .check_nested(true)
+                    .with_context(|| format!("in accounts.{name}.folders.{folder}"))?;
+            }
+        }
+        for (folder, config) in &self.folders {
+            config
+                .check_nested(true)
+                .with_context(|| format!("in folders.{folder}"))?;
+        }
+        Ok(())
+    }
+
+    fn check_nested(&self, folder: bool) -> Result<()> {
+        if let Some(key) = GLOBAL_KEYS.iter().find(|key| self.global_key_set(key)) {
+            anyhow::bail!("`{key}` can only be set at the top level");
+        }
+        if !self.accounts.is_empty() {
+            anyhow::bail!("`accounts` can only be set at the top level");
+        }
+        if folder && !self.folders.is_empty() {
+            anyhow::bail!("`folders` cannot be nested in a folder");
+        }
+        Ok(())
+    }
+
+    fn global_key_set(&self, key: &str) -> bool {
+        match key {
+            "cache_dir" => self.cache_dir.is_some(),
+            "himalaya_bin" => self.himalaya_bin.is_some(),
+            "threads" => self.threads.is_some(),
+            "compression" => self.compression.is_some(),
+            "compression_level" => self.compression_level.is_some(),
+            _ => false,
+        }
+    }
+
+    /// Effective settings of the whole cache, an account, or a folder of an
+    /// account. Later layers win: defaults, top level, `folders.<folder>`,
+    /// `accounts.<account>`, its `folders.<folder>`, the environment, then
+    /// the command line.
+    pub fn settings(&self, account: Option<&str>, folder: Option<&str>) -> Result<Settings> {
+        let base_dirs = BaseDirs::new().context("locate home directory")?;
+        let mut settings = Settings {
+            config: self.path.clone(),
+            cache_dir: default_cache_dir(&base_dirs),
+            himalaya_bin: None,
+            threads: None,
+            compression: CompressionKind::None,
+            compression_level: DEFAULT_COMPRESSION_LEVEL,
+            retries: 3,
+            retry_delay_ms: 2500,
+            page_size: 999,
+            sync: true,
+            bodies: true,
+            max_bodies: None,
+        };
+
+        let account_config = account.and_then(|account| self.accounts.get(account));
+        let layers = [
+            Some(self),
+            folder.and_then(|folder| self.folders.get(folder)),
+            account_config,
+            account_config.and_then(|config| folder.and_then(|folder| config.folders.get(folder))),
+            self.overrides.as_deref(),
+        ];
+        for layer in layers.into_iter().flatten() {
+            layer.apply(&mut settings, base_dirs.home_dir());
+        }
+        Ok(settings)
+    }
+
+    fn apply(&self, settings: &mut Settings, home: &Path) {
+        if let Some(cache_dir) = &self.cache_dir {
+            settings.cache_dir = expand_home(cache_dir, home);
+        }
+        if let Some(himalaya_bin) = &self.himalaya_bin {
+            settings.himalaya_bin = Some(expand_home(himalaya_bin, home));
+        }
+        if self.threads.is_some() {
+            settings.threads = self.threads;
+        }
+        if let Some(compression) = self.compression {
+            settings.compression = compression;
+        }
+        if let Some(level) = self.compression_level {
+            settings.compression_level = level;
+        }
+        if let Some(retries) = self.retries {
+            settings.retries = retries;
+        }
+        if let Some(delay) = self.retry_delay_ms {
+            settings.retry_delay_ms = delay;
+        }
+        if let Some(page_size) = self.page_size {
+            settings.page_size = page_size;
+        }
+        if let Some(sync) = self.sync {
+            settings.sync = sync;
+        }
+        if let Some(bodies) = self.bodies {
+            settings.bodies = bodies;
+        }
+        if self.max_bodies.is_some() {
+            settings.max_bodies = self.max_bodies;
+        }
+    }
+}
+
+impl Settings {
+    /// Compression to apply to newly cached message bodies.
+    pub fn compression(&self) -> Compression {
+        match self.compression {
+            CompressionKind::None => Compression::None,
+            CompressionKind::Zstd => Compression::Zstd(self.compression_level),
+        }
+    }
+}
+
+/// Config layer built from the [`ENV_OVERRIDES`] variables that are set.
+fn env_overrides() -> Result<Config> {
+    let mut table = toml::Table::new();
+    for (var, key) in ENV_OVERRIDES {
+        let Ok(value) = std::env::var(var) else {
+            continue;
+        };
+        let value = if let Ok(number) = value.parse::<i64>() {
+            toml::Value::Integer(number)
+        } else if let Ok(flag) = value.parse::<bool>() {
+            toml::Value::Boolean(flag)
+        } else {
+            toml::Value::String(value)
+        };
+        table.insert(key.to_string(), value);
+    }
+    toml::Value::Table(table)
+        .try_into()
+        .context("parse HIMALAYA_CACHE_* environment variables")
+}
+
+/// Resolve a leading `~` to the home directory.
+fn expand_home(path: &Path, home: &Path) -> PathBuf {
+    match path.strip_prefix("~") {
+        Ok(rest) => home.join(rest),
+        Err(_) => path.to_path_buf(),
+    }
+}
+
+/// `$XDG_DATA_HOME/himalaya-cache` (or the platform data directory), as the
+/// cache also holds imported messages that exist nowhere else. A cache left
+/// in `~/.local/share` by earlier versions keeps being used.
+fn default_cache_dir(base_dirs: &BaseDirs) -> PathBuf {
+    let cache_dir = base_dirs.data_dir().join("himalaya-cache");
+    let legacy = base_dirs
+        .home_dir()
+        .join(".local")
+        .join("share")
+        .join("himalaya-cache");
+    if !cache_dir.exists() && legacy.exists() {
+        legacy
+    } else {
+        cache_dir
+    }
+}
+
+fn default_path() -> Result<PathBuf> {
+    let base_dirs = BaseDirs::new().context("locate home directory")?;
+    Ok(base_dirs
+        .config_dir()
+        .join("himalaya-cache")
+        .join("config.toml"))
+}
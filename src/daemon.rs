@@ -0,0 +1,69 @@
+//! Thin client of `serve --socket`: read commands are forwarded to a
+//! running server of the same cache, which answers from the indices it keeps
+//! in memory. Without one they run in-process as usual.
+
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+
+use crate::CachedCommand;
+
+/// Environment variable disabling forwarding when set.
+pub const NO_DAEMON_ENV: &str = "HIMALAYA_CACHE_NO_DAEMON";
+
+/// Socket of the server of a cache directory:
+/// `$XDG_RUNTIME_DIR/himalaya-cache/<hash of the cache directory>.sock`.
+pub fn socket_path(cache_dir: &Path) -> Option<PathBuf> {
+    let runtime_dir = std::env::var_os("XDG_RUNTIME_DIR").filter(|dir| !dir.is_empty())?;
+    let hash: String = Sha256::digest(cache_dir.as_os_str().as_encoded_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+    Some(
+        PathBuf::from(runtime_dir)
+            .join("himalaya-cache")
+            .join(format!("{}.sock", &hash[..16])),
+    )
+}
+
+/// Output of `command` as printed by the server of `cache_dir`. None when no
+/// server runs or it fails to answer, in which case the command runs
+/// in-process, which also reports its errors the usual way.
+#[cfg(unix)]
+pub fn forward(cache_dir: &Path, command: &CachedCommand) -> Option<String> {
+    use serde_json::{Value, json};
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::Shutdown,
+        os::unix::net::UnixStream,
+        time::Duration,
+    };
+
+    if std::env::var_os(NO_DAEMON_ENV).is_some() {
+        return None;
+    }
+    let path = socket_path(cache_dir)?;
+    let mut stream = UnixStream::connect(path).ok()?;
+    stream
+        .set_read_timeout(Some(Duration::from_secs(60)))
+        .ok()?;
+
+    let request = json!({ "jsonrpc": "2.0", "id": 1, "method": "command", "params": command });
+    let mut line = serde_json::to_string(&request).ok()?;
+    line.push('\n');
+    stream.write_all(line.as_bytes()).ok()?;
+    stream.shutdown(Shutdown::Write).ok()?;
+
+    // Notifications of syncs running on the server may come first.
+    for line in BufReader::new(stream).lines() {
+        let response: Value = serde_json::from_str(&line.ok()?).ok()?;
+        if response.get("id") == Some(&json!(1)) {
+            return response.get("result")?.as_str().map(str::to_string);
+        }
+    }
+    None
+}
+
+#[cfg(not(unix))]
+pub fn forward(_cache_dir: &Path, _command: &CachedCommand) -> Option<String> {
+    None
+}
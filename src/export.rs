@@ -0,0 +1,202 @@
+//! Export of cached folders to mbox and Maildir.
+
+use anyhow::{Context, Result};
+use chrono::DateTime;
+use std::{
+    fs,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use crate::{Contacts, Envelope, parse_envelope_date};
+
+/// Cached message to export: its envelope and raw bytes.
+pub struct ExportMessage {
+    pub envelope: Envelope,
+    pub bytes: Vec<u8>,
+}
+
+/// Write messages to an mboxrd file, escaping body lines that start with
+/// `From ` (after any number of `>`) with an extra `>`. An existing file is
+/// only replaced with `force`.
+pub fn write_mbox(dest: &Path, messages: &[ExportMessage], force: bool) -> Result<()> {
+    if let Some(parent) = dest
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("create directory {}", parent.display()))?;
+    }
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .create_new(!force)
+        .truncate(true)
+        .open(dest);
+    let file = match file {
+        Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+            anyhow::bail!(
+                "{} already exists; pass --force to replace it",
+                dest.display()
+            )
+        }
+        file => file.with_context(|| format!("create {}", dest.display()))?,
+    };
+    let mut writer = BufWriter::new(file);
+    for message in messages {
+        write_mbox_message(&mut writer, message)
+            .with_context(|| format!("write {}", dest.display()))?;
+    }
+    writer
+        .flush()
+        .with_context(|| format!("write {}", dest.display()))
+}
+
+fn write_mbox_message(writer: &mut impl Write, message: &ExportMessage) -> std::io::Result<()> {
+    let sender = message
+        .envelope
+        .from
+        .as_ref()
+        .and_then(Contacts::first)
+        .and_then(|contact| contact.addr.as_deref())
+        .filter(|addr| !addr.is_empty() && !addr.contains(char::is_whitespace))
+        .unwrap_or("MAILER-DAEMON");
+    let date = parse_envelope_date(&message.envelope)
+        .map(|date| date.to_utc())
+        .unwrap_or(DateTime::UNIX_EPOCH);
+    writeln!(
+        writer,
+        "From {} {}",
+        sender,
+        date.format("%a %b %e %H:%M:%S %Y")
+    )?;
+
+    let text = normalize_newlines(&message.bytes);
+    for line in text.split_inclusive(|byte| *byte == b'\n') {
+        if line
+            .iter()
+            .skip_while(|byte| **byte == b'>')
+            .take(5)
+            .eq(b"From ")
+        {
+            writer.write_all(b">")?;
+        }
+        writer.write_all(line)?;
+    }
+    if !text.ends_with(b"\n") {
+        writer.write_all(b"\n")?;
+    }
+    writer.write_all(b"\n")
+}
+
+/// Write messages into a Maildir (`cur/`, `new/`, `tmp/`), encoding the
+/// envelope flags in the filename info suffix.
+pub fn write_maildir(dest: &Path, messages: &[ExportMessage]) -> Result<()> {
+    for sub in ["cur", "new", "tmp"] {
+        let dir = dest.join(sub);
+        fs::create_dir_all(&dir).with_context(|| format!("create directory {}", dir.display()))?;
+    }
+    for message in messages {
+        let timestamp = parse_envelope_date(&message.envelope)
+            .map(|date| date.timestamp())
+            .unwrap_or_default();
+        let unique: String = message
+            .envelope
+            .id
+            .chars()
+            .map(|ch| if ch.is_ascii_alphanumeric() { ch } else { '_' })
+            .collect();
+        let name = format!(
+            "{timestamp}.{unique}.himalaya-cache:2,{}",
+            maildir_flags(&message.envelope)
+        );
+        let path = dest.join("cur").join(name);
+        fs::write(&path, normalize_newlines(&message.bytes))
+            .with_context(|| format!("write {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Maildir info flags for the envelope flags, in ASCII order.
+fn maildir_flags(envelope: &Envelope) -> String {
+    let mut flags: Vec<char> = envelope
+        .flags
+        .iter()
+        .flatten()
+        .filter_map(|flag| match flag.to_lowercase().as_str() {
+            "draft" => Some('D'),
+            "flagged" => Some('F'),
+            "passed" | "forwarded" => Some('P'),
+            "answered" | "replied" => Some('R'),
+            "seen" => Some('S'),
+            "deleted" | "trashed" => Some('T'),
+            _ => None,
+        })
+        .collect();
+    flags.sort_unstable();
+    flags.dedup();
+    flags.into_iter().collect()
+}
+
+fn normalize_newlines(bytes: &[u8]) -> Vec<u8> {
+    let mut normalized = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] == b'\r' && bytes.get(index + 1) == Some(&b'\n') {
+            index += 1;
+            continue;
+        }
+        normalized.push(bytes[index]);
+        index += 1;
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(flags: &[&str], body: &str) -> ExportMessage {
+        ExportMessage {
+            envelope: serde_json::from_value(serde_json::json!({
+                "id": "1",
+                "flags": flags,
+                "subject": "hello",
+                "from": { "name": null, "addr": "alice@example.org" },
+                "to": null,
+                "date": "2024-03-01 09:30+00:00",
+                "has_attachment": false,
+            }))
+            .unwrap(),
+            bytes: body.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn mbox_escapes_from_lines() {
+        let mut out = Vec::new();
+        let body = "Subject: hello\r\n\r\nFrom here\r\n>From there\r\n Fromage\r\nFrom";
+        write_mbox_message(&mut out, &message(&[], body)).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "From alice@example.org Fri Mar  1 09:30:00 2024\n\
+             Subject: hello\n\
+             \n\
+             >From here\n\
+             >>From there\n \
+             Fromage\n\
+             From\n\
+             \n"
+        );
+    }
+
+    #[test]
+    fn maildir_flags_are_sorted_letters() {
+        let flags = |flags: &[&str]| maildir_flags(&message(flags, "").envelope);
+        assert_eq!(flags(&[]), "");
+        assert_eq!(flags(&["Seen", "Flagged", "Answered"]), "FRS");
+        assert_eq!(flags(&["seen", "Seen", "Draft", "Trashed"]), "DST");
+        assert_eq!(flags(&["Forwarded", "Replied", "Deleted"]), "PRT");
+        assert_eq!(flags(&["Recent", "$Junk"]), "");
+    }
+}
@@ -0,0 +1,297 @@
+//! Discovery of the himalaya binary and of the accounts in its config.
+
+use anyhow::{Context, Result};
+use directories::BaseDirs;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::OnceLock,
+};
+
+/// Environment variable naming the himalaya binary, tried first.
+pub const BIN_ENV: &str = "HIMALAYA_BIN";
+
+static BINARY: OnceLock<Result<Binary, String>> = OnceLock::new();
+static CONFIG_FILE: OnceLock<Option<PathBuf>> = OnceLock::new();
+static ACCOUNTS: OnceLock<Result<Accounts, String>> = OnceLock::new();
+
+/// Environment variable himalaya reads its config file from.
+pub const CONFIG_ENV: &str = "HIMALAYA_CONFIG";
+
+/// Folder aliases himalaya resolves when an account does not set them.
+const DEFAULT_FOLDER_ALIASES: &[(&str, &str)] = &[
+    ("inbox", "INBOX"),
+    ("sent", "Sent"),
+    ("drafts", "Drafts"),
+    ("trash", "Trash"),
+];
+
+/// Account settings read from himalaya's config file.
+#[derive(Debug, Default)]
+pub struct Accounts {
+    /// Account marked `default = true`.
+    pub default: Option<String>,
+    /// Folder aliases of each account, keyed by lowercase alias.
+    aliases: HashMap<String, HashMap<String, String>>,
+}
+
+/// The himalaya binary used by this process.
+#[derive(Debug)]
+pub struct Binary {
+    pub path: PathBuf,
+    version: OnceLock<Option<String>>,
+}
+
+/// Resolved binary as printed by `config show`.
+#[derive(Debug, Serialize)]
+pub struct Info<'a> {
+    pub path: &'a Path,
+    pub version: Option<&'a str>,
+    pub config: Option<&'a Path>,
+}
+
+impl Binary {
+    /// First line of `himalaya --version`, run once per process.
+    pub fn version(&self) -> Option<&str> {
+        self.version
+            .get_or_init(|| {
+                let output = Command::new(&self.path).arg("--version").output().ok()?;
+                let stdout = String::from_utf8_lossy(&output.stdout);
+                let line = stdout.lines().next()?.trim();
+                (output.status.success() && !line.is_empty()).then(|| line.to_string())
+            })
+            .as_deref()
+    }
+
+    pub fn info(&self) -> Info<'_> {
+        Info {
+            path: &self.path,
+            version: self.version(),
+            config: config_file(),
+        }
+    }
+}
+
+/// Locate himalaya once per process: `$HIMALAYA_BIN`, else the
+/// `himalaya_bin` config setting, else `$PATH`, then `~/.cargo/bin`.
+pub fn binary() -> Result<&'static Binary> {
+    BINARY
+        .get_or_init(|| {
+            let settings = crate::config::get().settings(None, None).ok();
+            resolve(
+                settings
+                    .and_then(|settings| settings.himalaya_bin)
+                    .as_deref(),
+            )
+        })
+        .as_ref()
+        .map_err(|message| anyhow::anyhow!("{message}"))
+}
+
+fn resolve(configured: Option<&Path>) -> Result<Binary, String> {
+    // An explicit choice is used as is: falling back to another himalaya
+    // would hide the mistake.
+    let explicit = std::env::var_os(BIN_ENV)
+        .map(|path| (format!("${BIN_ENV}"), PathBuf::from(path)))
+        .or_else(|| configured.map(|path| ("config himalaya_bin".to_string(), path.to_path_buf())));
+    if let Some((source, path)) = explicit {
+        if !is_executable(&path) {
+            return Err(format!(
+                "himalaya binary {} ({source}) is missing or not executable",
+                path.display()
+            ));
+        }
+        return Ok(Binary {
+            path,
+            version: OnceLock::new(),
+        });
+    }
+
+    let mut candidates: Vec<(String, PathBuf)> = Vec::new();
+    if let Some(paths) = std::env::var_os("PATH") {
+        for dir in std::env::split_paths(&paths) {
+            candidates.push(("$PATH".to_string(), dir.join("himalaya")));
+        }
+    }
+    if let Some(base_dirs) = BaseDirs::new() {
+        let path = base_dirs
+            .home_dir()
+            .join(".cargo")
+            .join("bin")
+            .join("himalaya");
+        candidates.push(("cargo install".to_string(), path));
+    }
+
+    match candidates.iter().find(|(_, path)| is_executable(path)) {
+        Some((_, path)) => Ok(Binary {
+            path: path.clone(),
+            version: OnceLock::new(),
+        }),
+        None => {
+            let tried: Vec<String> = candidates
+                .iter()
+                .map(|(source, path)| format!("  {} ({source})", path.display()))
+                .collect();
+            Err(format!(
+                "himalaya binary not found; set ${BIN_ENV} or himalaya_bin in the config. Tried:\n{}",
+                tried.join("\n")
+            ))
+        }
+    }
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    path.metadata()
+        .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+/// Record the himalaya config file given with `-c` for this process.
+pub fn set_config_file(path: Option<PathBuf>) {
+    let _ = CONFIG_FILE.set(path);
+}
+
+/// The himalaya config file given with `-c`, if any.
+pub fn config_file() -> Option<&'static Path> {
+    CONFIG_FILE.get().and_then(Option::as_deref)
+}
+
+/// The config file himalaya is pointed at for this process: `-c`, else
+/// `$HIMALAYA_CONFIG`. None when it reads one of its usual locations.
+pub fn chosen_config() -> Option<PathBuf> {
+    config_file()
+        .map(Path::to_path_buf)
+        .or_else(|| std::env::var_os(CONFIG_ENV).map(PathBuf::from))
+}
+
+/// Arguments put before every himalaya command: `-c <file>` when given.
+pub fn global_args() -> Vec<&'static OsStr> {
+    match config_file() {
+        Some(path) => vec![OsStr::new("-c"), path.as_os_str()],
+        None => Vec::new(),
+    }
+}
+
+/// Name of the cache namespace of a himalaya config file: its file stem and
+/// a hash of its absolute path, so the same file always maps to the same
+/// namespace and different files never share one.
+pub fn config_id(path: &Path) -> Result<String> {
+    let path = path
+        .canonicalize()
+        .with_context(|| format!("locate himalaya config {}", path.display()))?;
+    let hash: String = Sha256::digest(path.as_os_str().as_encoded_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+    let stem: String = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy())
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'))
+        .collect();
+    Ok(format!("{stem}-{}", &hash[..16]))
+}
+
+/// Accounts of himalaya's config file, read once per process. A missing
+/// config file yields no accounts.
+pub fn accounts() -> Result<&'static Accounts> {
+    ACCOUNTS
+        .get_or_init(|| read_accounts().map_err(|err| format!("{err:#}")))
+        .as_ref()
+        .map_err(|message| anyhow::anyhow!("{message}"))
+}
+
+impl Accounts {
+    /// Folder name of an alias such as `inbox` or `sent`, the way himalaya
+    /// resolves it: the account's `folder.aliases`, then the built-in
+    /// aliases, then the name itself.
+    pub fn folder(&self, account: &str, folder: &str) -> String {
+        let alias = folder.trim().to_lowercase();
+        self.aliases
+            .get(account)
+            .and_then(|aliases| aliases.get(&alias))
+            .cloned()
+            .or_else(|| {
+                DEFAULT_FOLDER_ALIASES
+                    .iter()
+                    .find(|(name, _)| *name == alias)
+                    .map(|(_, folder)| folder.to_string())
+            })
+            .unwrap_or_else(|| folder.to_string())
+    }
+}
+
+/// Paths himalaya reads its config from: `-c`, `$HIMALAYA_CONFIG`, then the
+/// usual locations.
+fn config_candidates() -> Vec<PathBuf> {
+    if let Some(path) = chosen_config() {
+        return vec![path];
+    }
+    usual_configs()
+}
+
+/// Whether `path` is one of the files himalaya reads without `-c`.
+pub fn is_usual_config(path: &Path) -> bool {
+    let Ok(path) = path.canonicalize() else {
+        return false;
+    };
+    usual_configs()
+        .iter()
+        .any(|usual| usual.canonicalize().is_ok_and(|usual| usual == path))
+}
+
+fn usual_configs() -> Vec<PathBuf> {
+    let mut candidates = Vec::new();
+    if let Some(base_dirs) = BaseDirs::new() {
+        candidates.push(base_dirs.config_dir().join("himalaya").join("config.toml"));
+        let home = base_dirs.home_dir();
+        candidates.push(home.join(".config").join("himalaya").join("config.toml"));
+        candidates.push(home.join(".himalayarc"));
+    }
+    candidates
+}
+
+fn read_accounts() -> Result<Accounts> {
+    let Some(path) = config_candidates().into_iter().find(|path| path.is_file()) else {
+        return Ok(Accounts::default());
+    };
+    let data = fs::read_to_string(&path).with_context(|| format!("read {}", path.display()))?;
+    let config: toml::Table =
+        toml::from_str(&data).with_context(|| format!("parse {}", path.display()))?;
+
+    let mut accounts = Accounts::default();
+    let entries = config.get("accounts").and_then(toml::Value::as_table);
+    for (name, account) in entries.into_iter().flatten() {
+        if account.get("default").and_then(toml::Value::as_bool) == Some(true) {
+            accounts.default = Some(name.clone());
+        }
+        // `folder.aliases` in himalaya 1.x, `folder.alias` and
+        // `folder-aliases` in earlier releases.
+        let folder = account.get("folder");
+        let aliases = folder
+            .and_then(|folder| folder.get("aliases").or_else(|| folder.get("alias")))
+            .or_else(|| account.get("folder-aliases"))
+            .and_then(toml::Value::as_table);
+        let aliases = aliases
+            .into_iter()
+            .flatten()
+            .filter_map(|(alias, folder)| {
+                Some((alias.to_lowercase(), folder.as_str()?.to_string()))
+            })
+            .collect();
+        accounts.aliases.insert(name.clone(), aliases);
+    }
+    Ok(accounts)
+}
@@ -0,0 +1,240 @@
+//! Import of mbox files and Maildirs into the cache.
+
+use anyhow::{Context, Result};
+use chrono::DateTime;
+use std::{fs, path::Path};
+
+use crate::{Contacts, Envelope, ExtraFields, mime};
+
+/// Message read from an archive, with the flags the archive recorded.
+pub struct ArchiveMessage {
+    pub bytes: Vec<u8>,
+    pub flags: Vec<String>,
+}
+
+/// Read every message of an mbox file or Maildir directory.
+pub fn read_archive(path: &Path) -> Result<Vec<ArchiveMessage>> {
+    if path.is_dir() {
+        read_maildir(path)
+    } else {
+        let data = fs::read(path).with_context(|| format!("read {}", path.display()))?;
+        Ok(read_mbox(&data))
+    }
+}
+
+/// Split an mbox file on `From ` separator lines, undoing mboxrd `>From `
+/// escaping. Flags come from the `Status` and `X-Status` headers.
+fn read_mbox(data: &[u8]) -> Vec<ArchiveMessage> {
+    let mut messages = Vec::new();
+    let mut current: Option<Vec<u8>> = None;
+    let mut previous_blank = true;
+    for line in data.split_inclusive(|byte| *byte == b'\n') {
+        if previous_blank && line.starts_with(b"From ") {
+            messages.extend(current.take().map(finish_mbox_message));
+            current = Some(Vec::new());
+            previous_blank = false;
+            continue;
+        }
+        previous_blank = line == b"\n" || line == b"\r\n";
+        let Some(message) = current.as_mut() else {
+            continue;
+        };
+        let quotes = line.iter().take_while(|byte| **byte == b'>').count();
+        if quotes > 0 && line[quotes..].starts_with(b"From ") {
+            message.extend_from_slice(&line[1..]);
+        } else {
+            message.extend_from_slice(line);
+        }
+    }
+    messages.extend(current.map(finish_mbox_message));
+    messages
+}
+
+fn finish_mbox_message(mut bytes: Vec<u8>) -> ArchiveMessage {
+    // Drop the blank line separating this message from the next one.
+    if bytes.ends_with(b"\n\n") {
+        bytes.pop();
+    } else if bytes.ends_with(b"\r\n\r\n") {
+        bytes.truncate(bytes.len() - 2);
+    }
+    let mut flags = Vec::new();
+    if let Some(message) = mime::parse(&bytes) {
+        let header = |name: &str| message.header_raw(name).unwrap_or_default().to_string();
+        if header("Status").contains('R') {
+            flags.push("Seen".to_string());
+        }
+        let x_status = header("X-Status");
+        for (letter, flag) in [
+            ('A', "Answered"),
+            ('F', "Flagged"),
+            ('D', "Deleted"),
+            ('T', "Draft"),
+        ] {
+            if x_status.contains(letter) {
+                flags.push(flag.to_string());
+            }
+        }
+    }
+    ArchiveMessage { bytes, flags }
+}
+
+/// Read the `cur/` and `new/` messages of a Maildir, taking flags from the
+/// `:2,` info suffix of the filenames.
+fn read_maildir(path: &Path) -> Result<Vec<ArchiveMessage>> {
+    let mut messages = Vec::new();
+    for sub in ["cur", "new"] {
+        let dir = path.join(sub);
+        if !dir.is_dir() {
+            continue;
+        }
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(&dir).with_context(|| format!("read {}", dir.display()))? {
+            let entry = entry.with_context(|| format!("read entry in {}", dir.display()))?;
+            if entry.path().is_file() {
+                entries.push(entry.path());
+            }
+        }
+        entries.sort();
+        for entry in entries {
+            let bytes = fs::read(&entry).with_context(|| format!("read {}", entry.display()))?;
+            let name = entry.file_name().unwrap_or_default().to_string_lossy();
+            let info = name
+                .rsplit_once(":2,")
+                .map(|(_, info)| info)
+                .unwrap_or_default();
+            let flags = info
+                .chars()
+                .filter_map(|letter| match letter {
+                    'D' => Some("Draft"),
+                    'F' => Some("Flagged"),
+                    'R' => Some("Answered"),
+                    'S' => Some("Seen"),
+                    'T' => Some("Deleted"),
+                    _ => None,
+                })
+                .map(str::to_string)
+                .collect();
+            messages.push(ArchiveMessage { bytes, flags });
+        }
+    }
+    if messages.is_empty() && !path.join("cur").is_dir() && !path.join("new").is_dir() {
+        anyhow::bail!("{} is not a Maildir (no cur/ or new/)", path.display());
+    }
+    Ok(messages)
+}
+
+/// Build envelope metadata for an imported message, in the same shape as
+/// `himalaya envelope list -o json`.
+pub fn envelope_for(id: String, message: &ArchiveMessage) -> Envelope {
+    let parsed = mime::parse(&message.bytes);
+    let contacts =
+        |address: Option<&mail_parser::Address>| Contacts::from_vec(mime::contacts(address));
+    let date = parsed
+        .as_ref()
+        .and_then(|parsed| parsed.date())
+        .and_then(|date| DateTime::parse_from_rfc3339(&date.to_rfc3339()).ok())
+        .map(|date| date.format("%Y-%m-%d %H:%M%:z").to_string());
+
+    let mut envelope = Envelope {
+        id,
+        flags: Some(message.flags.clone()),
+        subject: parsed
+            .as_ref()
+            .and_then(|parsed| parsed.subject())
+            .map(str::to_string),
+        from: contacts(parsed.as_ref().and_then(|parsed| parsed.from())),
+        to: contacts(parsed.as_ref().and_then(|parsed| parsed.to())),
+        date,
+        has_attachment: parsed.as_ref().map(|parsed| parsed.attachment_count() > 0),
+        cc: None,
+        bcc: None,
+        reply_to: None,
+        message_id: None,
+        extra: ExtraFields::new(),
+    };
+    envelope.add_headers(mime::envelope_headers(&message.bytes));
+    envelope
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::export::{self, ExportMessage};
+
+    fn messages() -> Vec<ExportMessage> {
+        let message = |id: &str, flags: &[&str], body: &str| ExportMessage {
+            envelope: serde_json::from_value(serde_json::json!({
+                "id": id,
+                "flags": flags,
+                "subject": "hello",
+                "from": { "name": null, "addr": "alice@example.org" },
+                "to": null,
+                "date": "2024-03-01 09:30+00:00",
+                "has_attachment": false,
+            }))
+            .unwrap(),
+            bytes: body.as_bytes().to_vec(),
+        };
+        vec![
+            message(
+                "1",
+                &["Seen", "Flagged"],
+                "Subject: one\r\n\r\nFrom the start\r\n>From quoted\r\n\r\nFrom\r\n",
+            ),
+            message(
+                "2",
+                &["Answered", "Draft", "Deleted"],
+                "Subject: two\n\nbye",
+            ),
+            message("3", &[], "Subject: three\n\n\n"),
+        ]
+    }
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("himalaya-cache-test-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn bodies(messages: &[ArchiveMessage]) -> Vec<String> {
+        messages
+            .iter()
+            .map(|message| String::from_utf8(message.bytes.clone()).unwrap())
+            .collect()
+    }
+
+    const BODIES: [&str; 3] = [
+        "Subject: one\n\nFrom the start\n>From quoted\n\nFrom\n",
+        "Subject: two\n\nbye\n",
+        "Subject: three\n\n\n",
+    ];
+
+    #[test]
+    fn mbox_round_trip() {
+        let path = temp_path("round-trip.mbox");
+        export::write_mbox(&path, &messages(), false).unwrap();
+        let read = read_mbox(&fs::read(&path).unwrap());
+        assert_eq!(bodies(&read), BODIES);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn maildir_round_trip() {
+        let path = temp_path("round-trip-maildir");
+        export::write_maildir(&path, &messages()).unwrap();
+        let read = read_maildir(&path).unwrap();
+        assert_eq!(bodies(&read), [BODIES[0], "Subject: two\n\nbye", BODIES[2]]);
+        let flags: Vec<Vec<String>> = read.into_iter().map(|message| message.flags).collect();
+        assert_eq!(
+            flags,
+            [
+                vec!["Flagged", "Seen"],
+                vec!["Draft", "Answered", "Deleted"],
+                vec![],
+            ]
+        );
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -1,12 +1,13 @@
 use anyhow::{Context, Result};
 use chrono::{DateTime, FixedOffset};
 use clap::{Args, Parser, Subcommand};
-use directories::BaseDirs;
+use directories::UserDirs;
 use indicatif::{ProgressBar, ProgressStyle};
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
+    cell::OnceCell,
+    collections::{HashMap, HashSet},
     fs,
     io::{self, Write},
     path::{Path, PathBuf},
@@ -15,11 +16,40 @@ use std::{
     time::Duration,
 };
 
+mod attachment;
+mod config;
+mod daemon;
+mod export;
+mod himalaya;
+mod import;
+mod mime;
+mod query;
+mod search;
+mod serve;
+mod store;
+mod threading;
+
+use config::Settings;
+use query::{Filter, Query};
+use store::Store;
+
+/// Prefix of the ids of imported messages, kept apart from server ids.
+const IMPORT_ID_PREFIX: &str = "import-";
+
 /// Himalaya cache CLI.
 #[derive(Parser)]
 #[command(name = "himalaya-cache")]
 #[command(about = "Cache data from the himalaya CLI", version)]
 struct Cli {
+    /// Config file to use instead of ~/.config/himalaya-cache/config.toml.
+    #[arg(long, global = true)]
+    cache_config: Option<PathBuf>,
+    /// Cache directory, overriding HIMALAYA_CACHE_DIR and the config.
+    #[arg(long, global = true)]
+    cache_dir: Option<PathBuf>,
+    /// Himalaya config file, passed to himalaya; each one gets its own cache.
+    #[arg(short = 'c', long = "config", global = true)]
+    himalaya_config: Option<PathBuf>,
     #[command(subcommand)]
     command: Commands,
 }
@@ -28,12 +58,32 @@ struct Cli {
 enum Commands {
     /// Sync accounts, folders, and messages from himalaya.
     Sync(SyncArgs),
+    /// Read cached account data.
+    Account(AccountArgs),
     /// Read cached folder data.
     Folder(FolderArgs),
     /// Read cached message data.
     Message(MessageArgs),
     /// Read cached envelope data.
     Envelope(EnvelopeArgs),
+    /// Read cached attachment data.
+    Attachment(AttachmentArgs),
+    /// Change message flags through himalaya and in the cache.
+    Flag(FlagArgs),
+    /// Search cached messages.
+    Search(SearchArgs),
+    /// Export a cached folder to mbox or Maildir.
+    Export(ExportArgs),
+    /// Import an mbox file or Maildir into a cached folder.
+    Import(ImportArgs),
+    /// Manage the cache storage backend.
+    Store(StoreArgs),
+    /// Upgrade the cache to the current layout version.
+    Migrate(MigrateArgs),
+    /// Inspect the himalaya-cache configuration.
+    Config(ConfigArgs),
+    /// Serve cached data to editors over a persistent connection.
+    Serve(ServeArgs),
 }
 
 #[derive(Args)]
@@ -46,6 +96,25 @@ struct SyncArgs {
     folder: Option<String>,
 }
 
+#[derive(Subcommand)]
+enum AccountCommand {
+    /// List cached accounts, asking himalaya when none are cached.
+    List(AccountListArgs),
+}
+
+#[derive(Args)]
+struct AccountArgs {
+    #[command(subcommand)]
+    command: AccountCommand,
+}
+
+#[derive(Args)]
+struct AccountListArgs {
+    /// Output format: `json` (default) or `plain`.
+    #[arg(short = 'o', long, default_value = "json")]
+    output: String,
+}
+
 #[derive(Subcommand)]
 enum FolderCommand {
     /// List cached folders for an account.
@@ -58,11 +127,11 @@ struct FolderArgs {
     command: FolderCommand,
 }
 
-#[derive(Args)]
+#[derive(Args, Serialize, Deserialize)]
 struct FolderListArgs {
-    /// Account name to read cached folders for.
+    /// Account name to read cached folders for (the default account when omitted).
     #[arg(long)]
-    account: String,
+    account: Option<String>,
 }
 
 #[derive(Subcommand)]
@@ -77,14 +146,29 @@ struct MessageArgs {
     command: MessageCommand,
 }
 
-#[derive(Args)]
+#[derive(Args, Serialize, Deserialize)]
 struct MessageReadArgs {
-    /// Account name to read cached message for.
+    /// Account name to read cached message for (the default account when omitted).
     #[arg(long)]
-    account: String,
-    /// Folder name to read cached message for.
+    account: Option<String>,
+    /// Folder name to read cached message for (the inbox of the account when omitted).
     #[arg(long)]
-    folder: String,
+    folder: Option<String>,
+    /// Headers to show above the body (defaults to From, To, Cc, Subject).
+    #[arg(short = 'H', long = "header", alias = "headers", value_delimiter = ',')]
+    headers: Vec<String>,
+    /// Print the original message bytes unchanged, without decoding.
+    #[arg(long)]
+    raw: bool,
+    /// Write the original message bytes to this file instead (implies --raw).
+    #[arg(long)]
+    output_file: Option<PathBuf>,
+    /// Print the body only.
+    #[arg(long)]
+    no_headers: bool,
+    /// Read the text/html parts instead of the text/plain ones.
+    #[arg(long)]
+    html: bool,
     /// Message id to read.
     id: String,
 }
@@ -93,6 +177,8 @@ struct MessageReadArgs {
 enum EnvelopeCommand {
     /// List cached envelopes for an account and folder.
     List(EnvelopeListArgs),
+    /// Show cached envelopes as conversation threads.
+    Thread(EnvelopeThreadArgs),
 }
 
 #[derive(Args)]
@@ -101,22 +187,293 @@ struct EnvelopeArgs {
     command: EnvelopeCommand,
 }
 
-#[derive(Args)]
+#[derive(Args, Serialize, Deserialize)]
 struct EnvelopeListArgs {
-    /// Account name to read cached envelopes for.
+    /// Account name to read cached envelopes for (the default account when omitted).
     #[arg(long)]
-    account: String,
-    /// Folder name to read cached envelopes for.
+    account: Option<String>,
+    /// Folder name to read cached envelopes for (the inbox of the account when omitted).
+    #[arg(long)]
+    folder: Option<String>,
+    /// Show envelopes as conversation threads, like `envelope thread`.
+    #[arg(long)]
+    thread: bool,
+    /// Output format used with --thread: `json` (default) or `plain`.
+    #[arg(short = 'o', long, default_value = "json")]
+    output: String,
+    /// Page number, starting at 1.
+    #[arg(short = 'p', long, default_value_t = 1)]
+    page: usize,
+    /// Number of envelopes per page (all on one page by default).
+    #[arg(short = 's', long)]
+    page_size: Option<usize>,
+    /// Also show the cc, bcc, reply_to and message_id read from cached
+    /// bodies, which himalaya's own output lacks.
+    #[arg(long)]
+    #[serde(default)]
+    extra_headers: bool,
+    /// Filter and sort query, e.g. `subject foo and not flag seen order by date desc`.
+    #[arg(trailing_var_arg = true)]
+    query: Vec<String>,
+}
+
+#[derive(Args)]
+struct EnvelopeThreadArgs {
+    /// Account name to read cached envelopes for (the default account when omitted).
+    #[arg(long)]
+    account: Option<String>,
+    /// Only show threads involving this folder; threads still include
+    /// messages from the other folders of the account.
+    #[arg(long)]
+    folder: Option<String>,
+    /// Output format: `json` (default) or `plain`.
+    #[arg(short = 'o', long, default_value = "json")]
+    output: String,
+    /// Only show threads with a message matching this query.
+    #[arg(trailing_var_arg = true)]
+    query: Vec<String>,
+}
+
+#[derive(Subcommand)]
+enum AttachmentCommand {
+    /// List attachments of a cached message.
+    List(AttachmentListArgs),
+    /// Download attachments of cached messages.
+    Download(AttachmentDownloadArgs),
+}
+
+#[derive(Args)]
+struct AttachmentArgs {
+    #[command(subcommand)]
+    command: AttachmentCommand,
+}
+
+#[derive(Args)]
+struct AttachmentListArgs {
+    /// Account name to read the cached message for (the default account when omitted).
+    #[arg(long)]
+    account: Option<String>,
+    /// Folder name to read the cached message for (the inbox of the account when omitted).
+    #[arg(long)]
+    folder: Option<String>,
+    /// Message id to list attachments of.
+    id: String,
+}
+
+#[derive(Args)]
+struct AttachmentDownloadArgs {
+    /// Account name to read cached messages for (the default account when omitted).
+    #[arg(long)]
+    account: Option<String>,
+    /// Folder name to read cached messages for (the inbox of the account when omitted).
+    #[arg(long)]
+    folder: Option<String>,
+    /// Directory to write attachments to (defaults to the downloads directory).
+    #[arg(long)]
+    dir: Option<PathBuf>,
+    /// Message ids to download attachments of.
+    #[arg(required = true)]
+    ids: Vec<String>,
+}
+
+/// Attachment entry printed by `attachment list`.
+#[derive(Debug, Serialize)]
+struct AttachmentEntry {
+    filename: Option<String>,
+    mime: String,
+    size: usize,
+}
+
+/// Search result printed by `search`: the envelope as `envelope list` prints
+/// it, plus where it is cached when the search spans several folders.
+#[derive(Debug, Serialize)]
+struct SearchHit {
+    #[serde(flatten)]
+    envelope: Envelope,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    account: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    folder: Option<String>,
+}
+
+#[derive(Args, Serialize, Deserialize)]
+struct SearchArgs {
+    /// Restrict the search to an account.
+    #[arg(long)]
+    account: Option<String>,
+    /// Restrict the search to a folder.
+    #[arg(long)]
+    folder: Option<String>,
+    /// Rank results by `relevance` (default) or `date`.
+    #[arg(long, default_value = "relevance")]
+    sort: String,
+    /// Also show the cc, bcc, reply_to and message_id read from cached
+    /// bodies, as `envelope list --extra-headers` does.
+    #[arg(long)]
+    #[serde(default)]
+    extra_headers: bool,
+    /// Search terms; every term must match.
+    #[arg(required = true)]
+    terms: Vec<String>,
+}
+
+#[derive(Subcommand)]
+enum FlagCommand {
+    /// Add flags to messages.
+    Add(FlagChangeArgs),
+    /// Replace the flags of messages.
+    Set(FlagChangeArgs),
+    /// Remove flags from messages.
+    Remove(FlagChangeArgs),
+}
+
+#[derive(Args)]
+struct FlagArgs {
+    #[command(subcommand)]
+    command: FlagCommand,
+}
+
+#[derive(Args)]
+struct FlagChangeArgs {
+    /// Account of the messages.
+    #[arg(short = 'a', long)]
+    account: Option<String>,
+    /// Folder of the messages (the inbox of the account when omitted).
+    #[arg(short = 'f', long)]
+    folder: Option<String>,
+    /// Message ids followed by flag names, as for `himalaya flag`.
+    ids_and_flags: Vec<String>,
+}
+
+#[derive(Args)]
+struct ExportArgs {
+    /// Account name to export cached messages for (the default account when omitted).
+    #[arg(long)]
+    account: Option<String>,
+    /// Folder name to export cached messages for.
+    #[arg(long)]
+    folder: String,
+    /// Export format: `mbox` or `maildir`.
+    #[arg(long)]
+    format: String,
+    /// Replace an existing destination mbox file.
+    #[arg(long)]
+    force: bool,
+    /// Destination mbox file or Maildir directory.
+    dest: PathBuf,
+}
+
+#[derive(Args)]
+struct ImportArgs {
+    /// Account name to import messages into (the default account when omitted).
+    #[arg(long)]
+    account: Option<String>,
+    /// Folder name to import messages into.
     #[arg(long)]
     folder: String,
+    /// mbox file or Maildir directory to import.
+    path: PathBuf,
+}
+
+#[derive(Subcommand)]
+enum StoreCommand {
+    /// Move the cache to another storage backend.
+    Migrate(StoreMigrateArgs),
+    /// Compress every cached message body in place with zstd.
+    Compress(StoreCompressArgs),
+    /// Store identical cached message bodies once.
+    Dedup,
+}
+
+#[derive(Args)]
+struct StoreArgs {
+    #[command(subcommand)]
+    command: StoreCommand,
+}
+
+#[derive(Args)]
+struct StoreMigrateArgs {
+    /// Backend to migrate to: `sqlite` or `fs` (the directory layout).
+    #[arg(long)]
+    to: String,
+    /// Keep the old files after a successful migration (a database is kept
+    /// as `cache.sqlite.bak`).
+    #[arg(long)]
+    keep: bool,
+}
+
+#[derive(Args)]
+struct MigrateArgs {
+    /// Print the pending migrations without running them.
+    #[arg(long)]
+    dry_run: bool,
+}
+
+#[derive(Args)]
+struct ServeArgs {
+    /// Speak line-delimited JSON-RPC 2.0 on stdin and stdout.
+    #[arg(long)]
+    stdio: bool,
+    /// Listen on a Unix socket under `$XDG_RUNTIME_DIR`, where read commands
+    /// of this cache are forwarded.
+    #[arg(long, conflicts_with = "stdio")]
+    socket: bool,
+    /// Serve a REST API on this address, e.g. `127.0.0.1:8025`.
+    #[arg(long, value_name = "ADDRESS", conflicts_with_all = ["stdio", "socket"])]
+    http: Option<String>,
+    /// Bearer token required by the REST API (defaults to
+    /// `$HIMALAYA_CACHE_TOKEN`).
+    #[arg(long, requires = "http")]
+    token: Option<String>,
+    /// Let the REST API start syncs; it is read-only otherwise.
+    #[arg(long, requires = "http")]
+    allow_sync: bool,
+    /// Also run a full sync every this many seconds.
+    #[arg(long)]
+    sync_interval: Option<u64>,
+}
+
+#[derive(Subcommand)]
+enum ConfigCommand {
+    /// Print the effective settings, after overrides and environment.
+    Show(ConfigShowArgs),
+}
+
+#[derive(Args)]
+struct ConfigArgs {
+    #[command(subcommand)]
+    command: ConfigCommand,
+}
+
+#[derive(Args)]
+struct ConfigShowArgs {
+    /// Account whose overrides to apply.
+    #[arg(long)]
+    account: Option<String>,
+    /// Folder whose overrides to apply.
+    #[arg(long)]
+    folder: Option<String>,
 }
 
+#[derive(Args)]
+struct StoreCompressArgs {
+    /// zstd level, defaulting to `compression_level` from the config.
+    #[arg(long)]
+    level: Option<i32>,
+}
+
+/// Fields himalaya emits that the cache does not interpret, kept so cached
+/// output matches himalaya's own.
+type ExtraFields = serde_json::Map<String, serde_json::Value>;
+
 /// Account entry from `himalaya account list -o json`.
 #[derive(Debug, Deserialize, Serialize)]
 struct Account {
     name: String,
     backend: Option<String>,
     default: Option<bool>,
+    #[serde(flatten)]
+    extra: ExtraFields,
 }
 
 /// Folder entry from `himalaya folder list -o json`.
@@ -124,35 +481,142 @@ struct Account {
 struct Folder {
     name: String,
     desc: Option<String>,
+    #[serde(flatten)]
+    extra: ExtraFields,
 }
 
 /// Envelope entry from `himalaya envelope list -o json`.
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 struct Envelope {
     id: String,
     flags: Option<Vec<String>>,
     subject: Option<String>,
-    from: Option<Contact>,
-    to: Option<Contact>,
+    from: Option<Contacts>,
+    to: Option<Contacts>,
     date: Option<String>,
     has_attachment: Option<bool>,
+    /// Address and identity headers read from the cached body during sync;
+    /// the lists are empty, not missing, once a body without them was read.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cc: Option<Vec<Contact>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    bcc: Option<Vec<Contact>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reply_to: Option<Vec<Contact>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    message_id: Option<String>,
+    #[serde(flatten)]
+    extra: ExtraFields,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+impl Envelope {
+    /// Every contact of the envelope, senders first.
+    fn contacts(&self) -> impl Iterator<Item = &Contact> {
+        let lists = [&self.cc, &self.bcc, &self.reply_to];
+        self.from
+            .iter()
+            .chain(&self.to)
+            .flat_map(Contacts::iter)
+            .chain(lists.into_iter().flatten().flatten())
+    }
+
+    /// Fill the header fields himalaya leaves out from the raw message.
+    fn add_headers(&mut self, headers: mime::EnvelopeHeaders) {
+        self.cc = Some(headers.cc);
+        self.bcc = Some(headers.bcc);
+        self.reply_to = Some(headers.reply_to);
+        self.message_id = headers.message_id;
+    }
+
+    /// Drop the header fields himalaya leaves out, so the envelope matches
+    /// himalaya's own output.
+    fn clear_headers(&mut self) {
+        self.cc = None;
+        self.bcc = None;
+        self.reply_to = None;
+        self.message_id = None;
+    }
+
+    /// Whether the header fields were already read from the body, even if
+    /// it had none of them.
+    fn has_headers(&self) -> bool {
+        self.cc.is_some()
+    }
+}
+
+/// A single contact or a list of them; himalaya emits either for from/to.
+/// The original shape is kept when written back.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(untagged)]
+enum Contacts {
+    One(Contact),
+    Many(Vec<Contact>),
+}
+
+impl Contacts {
+    /// Build from parsed addresses, as a single contact when there is one.
+    fn from_vec(mut contacts: Vec<Contact>) -> Option<Self> {
+        match contacts.len() {
+            0 => None,
+            1 => contacts.pop().map(Contacts::One),
+            _ => Some(Contacts::Many(contacts)),
+        }
+    }
+
+    fn iter(&self) -> std::slice::Iter<'_, Contact> {
+        match self {
+            Contacts::One(contact) => std::slice::from_ref(contact).iter(),
+            Contacts::Many(contacts) => contacts.iter(),
+        }
+    }
+
+    fn first(&self) -> Option<&Contact> {
+        self.iter().next()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 struct Contact {
     name: Option<String>,
     addr: Option<String>,
+    #[serde(flatten)]
+    extra: ExtraFields,
 }
 
 fn main() -> Result<()> {
-    let raw_args: Vec<String> = std::env::args().collect();
+    let args: Vec<String> = std::env::args().collect();
+    let (args, config_paths) = take_flag_values(&args, &["--cache-config"]);
+    let (args, cache_dirs) = take_flag_values(&args, &["--cache-dir"]);
+    let (raw_args, himalaya_configs) = take_flag_values(&args, &["-c", "--config"]);
+    himalaya::set_config_file(himalaya_configs.last().map(PathBuf::from));
+    config::init(
+        config_paths.last().map(PathBuf::from),
+        cache_dirs.last().map(PathBuf::from),
+    )?;
+    if let Some(threads) = settings()?.threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()
+            .context("start download threads")?;
+    }
+
     if raw_args.len() <= 1 {
         let cli = Cli::parse();
         return match cli.command {
             Commands::Sync(args) => run_sync(args),
+            Commands::Account(args) => run_account(args),
             Commands::Folder(args) => run_folder(args),
             Commands::Message(args) => run_message(args),
             Commands::Envelope(args) => run_envelope(args),
+            Commands::Attachment(args) => run_attachment(args),
+            Commands::Flag(args) => run_flag(args),
+            Commands::Search(args) => run_search(args),
+            Commands::Export(args) => run_export(args),
+            Commands::Import(args) => run_import(args),
+            Commands::Store(args) => run_store(args),
+            Commands::Migrate(args) => run_migrate(args),
+            Commands::Config(args) => run_config(args),
+            Commands::Serve(args) => run_serve(args),
         };
     }
 
@@ -167,6 +631,10 @@ fn try_run_internal(args: &[String]) -> Option<Result<()>> {
     let command = args.first()?.as_str();
     match command {
         "sync" => Some(parse_and_run_sync(&args[1..])),
+        "account" => match args.get(1).map(String::as_str) {
+            Some("list") => Some(parse_and_run_account_list(&args[2..])),
+            _ => None,
+        },
         "folder" => match args.get(1).map(String::as_str) {
             Some("list") => Some(parse_and_run_folder_list(&args[2..])),
             _ => None,
@@ -177,6 +645,38 @@ fn try_run_internal(args: &[String]) -> Option<Result<()>> {
         },
         "envelope" => match args.get(1).map(String::as_str) {
             Some("list") => Some(parse_and_run_envelope_list(&args[2..])),
+            Some("thread") => Some(parse_and_run_envelope_thread(&args[2..])),
+            _ => None,
+        },
+        "attachment" => match args.get(1).map(String::as_str) {
+            Some("list") => Some(parse_and_run_attachment_list(&args[2..])),
+            Some("download") => Some(parse_and_run_attachment_download(&args[2..])),
+            _ => None,
+        },
+        "flag" => match args.get(1).map(String::as_str) {
+            Some(action @ ("add" | "set" | "remove")) => {
+                Some(parse_and_run_flag(action, &args[2..]))
+            }
+            _ => None,
+        },
+        "search" => Some(parse_and_run_search(&args[1..])),
+        "export" => Some(parse_and_run_export(&args[1..])),
+        "import" => Some(parse_and_run_import(&args[1..])),
+        "config" => match args.get(1).map(String::as_str) {
+            Some("show") => Some(parse_and_run_config_show(&args[2..])),
+            _ => None,
+        },
+        "serve" => Some(parse_and_run_serve(&args[1..])),
+        "migrate" => {
+            let (_, switches) = take_switches(&args[1..], &["--dry-run"]);
+            Some(run_migrate(MigrateArgs {
+                dry_run: switches.contains("--dry-run"),
+            }))
+        }
+        "store" => match args.get(1).map(String::as_str) {
+            Some("migrate") => Some(parse_and_run_store_migrate(&args[2..])),
+            Some("compress") => Some(parse_and_run_store_compress(&args[2..])),
+            Some("dedup") => Some(dedup_store()),
             _ => None,
         },
         _ => None,
@@ -184,10 +684,12 @@ fn try_run_internal(args: &[String]) -> Option<Result<()>> {
 }
 
 fn run_himalaya_passthrough(args: &[String]) -> Result<()> {
-    let status = Command::new(himalaya_path()?)
+    let himalaya = himalaya::binary()?;
+    let status = Command::new(&himalaya.path)
+        .args(himalaya::global_args())
         .args(args)
         .status()
-        .with_context(|| "run himalaya")?;
+        .with_context(|| format!("run {}", himalaya.path.display()))?;
     if status.success() {
         Ok(())
     } else {
@@ -204,47 +706,258 @@ fn parse_and_run_sync(args: &[String]) -> Result<()> {
     run_sync(sync_args)
 }
 
+fn parse_and_run_account_list(args: &[String]) -> Result<()> {
+    let (flags, _) = parse_args(args, &["-o", "--output"], 0);
+    list_cached_accounts(AccountListArgs {
+        output: output_flag(&flags),
+    })
+}
+
 fn parse_and_run_folder_list(args: &[String]) -> Result<()> {
     let (flags, _) = parse_args(args, &["--account"], 0);
-    let account = flags
-        .get("--account")
-        .cloned()
-        .context("--account is required")?;
-    list_cached_folders(FolderListArgs { account })
+    let account = resolve_account(flags.get("--account").cloned())?;
+    list_cached_folders(FolderListArgs {
+        account: Some(account),
+    })
 }
 
 fn parse_and_run_message_read(args: &[String]) -> Result<()> {
-    let (flags, positionals) = parse_args(args, &["--account", "--folder"], 1);
-    let account = flags
-        .get("--account")
-        .cloned()
-        .context("--account is required")?;
-    let folder = flags
-        .get("--folder")
-        .cloned()
-        .context("--folder is required")?;
+    let (args, switches) = take_switches(args, &["--raw", "--no-headers", "--html"]);
+    let (args, headers) = take_flag_values(&args, &["-H", "--header", "--headers"]);
+    let (flags, positionals) = parse_args(&args, &["--account", "--folder", "--output-file"], 1);
+    let account = resolve_account(flags.get("--account").cloned())?;
     let id = positionals
         .first()
         .cloned()
         .context("message id is required")?;
     read_cached_message(MessageReadArgs {
-        account,
-        folder,
+        account: Some(account),
+        folder: flags.get("--folder").cloned(),
+        headers: headers
+            .iter()
+            .flat_map(|value| value.split(','))
+            .map(|name| name.trim().to_string())
+            .filter(|name| !name.is_empty())
+            .collect(),
+        raw: switches.contains("--raw"),
+        output_file: flags.get("--output-file").map(PathBuf::from),
+        no_headers: switches.contains("--no-headers"),
+        html: switches.contains("--html"),
         id,
     })
 }
 
 fn parse_and_run_envelope_list(args: &[String]) -> Result<()> {
-    let (flags, _) = parse_args(args, &["--account", "--folder"], 0);
-    let account = flags
-        .get("--account")
+    let (args, switches) = take_switches(args, &["--thread", "--extra-headers"]);
+    let (flags, query) = parse_args(
+        &args,
+        &[
+            "--account",
+            "--folder",
+            "-o",
+            "--output",
+            "-p",
+            "--page",
+            "-s",
+            "--page-size",
+        ],
+        0,
+    );
+    let account = resolve_account(flags.get("--account").cloned())?;
+    let number_flag = |long: &str, short: &str| {
+        flags
+            .get(long)
+            .or_else(|| flags.get(short))
+            .map(|value| match value.parse::<usize>() {
+                Ok(0) => anyhow::bail!("{long} must be at least 1"),
+                parsed => parsed.with_context(|| format!("invalid {long} {value:?}")),
+            })
+            .transpose()
+    };
+    list_cached_envelopes(EnvelopeListArgs {
+        account: Some(account),
+        folder: flags.get("--folder").cloned(),
+        thread: switches.contains("--thread"),
+        output: output_flag(&flags),
+        page: number_flag("--page", "-p")?.unwrap_or(1),
+        page_size: number_flag("--page-size", "-s")?,
+        extra_headers: switches.contains("--extra-headers"),
+        query,
+    })
+}
+
+fn parse_and_run_flag(action: &str, args: &[String]) -> Result<()> {
+    let (flags, ids_and_flags) = parse_args(args, &["-a", "--account", "-f", "--folder"], 1);
+    let args = FlagChangeArgs {
+        account: flags.get("--account").or_else(|| flags.get("-a")).cloned(),
+        folder: flags.get("--folder").or_else(|| flags.get("-f")).cloned(),
+        ids_and_flags,
+    };
+    change_flags(action, args)
+}
+
+fn parse_and_run_envelope_thread(args: &[String]) -> Result<()> {
+    let (flags, query) = parse_args(args, &["--account", "--folder", "-o", "--output"], 0);
+    let account = resolve_account(flags.get("--account").cloned())?;
+    thread_cached_envelopes(EnvelopeThreadArgs {
+        account: Some(account),
+        folder: flags.get("--folder").cloned(),
+        output: output_flag(&flags),
+        query,
+    })
+}
+
+/// `--account` when given, otherwise the default account of the himalaya
+/// config or, failing that, of the cached account list.
+fn resolve_account(account: Option<String>) -> Result<String> {
+    if let Some(account) = account {
+        return Ok(account);
+    }
+    if let Some(account) = &himalaya::accounts()?.default {
+        return Ok(account.clone());
+    }
+    open_store()?
+        .accounts()?
+        .into_iter()
+        .flatten()
+        .find(|account| account.default == Some(true))
+        .map(|account| account.name)
+        .context("--account is required: no default account in the himalaya config or the cache")
+}
+
+/// Folder name for `--folder`, resolving himalaya folder aliases such as
+/// `inbox` or `sent`; without a folder, the inbox of the account.
+fn resolve_folder(account: &str, folder: Option<String>) -> Result<String> {
+    let folder = folder.unwrap_or_else(|| "inbox".to_string());
+    Ok(himalaya::accounts()?.folder(account, &folder))
+}
+
+/// Value of `-o`/`--output`, defaulting to `json`.
+fn output_flag(flags: &HashMap<String, String>) -> String {
+    flags
+        .get("--output")
+        .or_else(|| flags.get("-o"))
+        .cloned()
+        .unwrap_or_else(|| "json".to_string())
+}
+
+fn parse_and_run_attachment_list(args: &[String]) -> Result<()> {
+    let (flags, positionals) = parse_args(args, &["--account", "--folder"], 1);
+    let account = resolve_account(flags.get("--account").cloned())?;
+    let id = positionals
+        .first()
         .cloned()
-        .context("--account is required")?;
-    let folder = flags
-        .get("--folder")
+        .context("message id is required")?;
+    list_cached_attachments(AttachmentListArgs {
+        account: Some(account),
+        folder: flags.get("--folder").cloned(),
+        id,
+    })
+}
+
+fn parse_and_run_attachment_download(args: &[String]) -> Result<()> {
+    let (flags, ids) = parse_args(args, &["--account", "--folder", "--dir"], 1);
+    let account = resolve_account(flags.get("--account").cloned())?;
+    if ids.is_empty() {
+        anyhow::bail!("message id is required");
+    }
+    download_cached_attachments(AttachmentDownloadArgs {
+        account: Some(account),
+        folder: flags.get("--folder").cloned(),
+        dir: flags.get("--dir").map(PathBuf::from),
+        ids,
+    })
+}
+
+fn parse_and_run_search(args: &[String]) -> Result<()> {
+    let (args, switches) = take_switches(args, &["--extra-headers"]);
+    let (flags, terms) = parse_args(&args, &["--account", "--folder", "--sort"], 1);
+    run_search(SearchArgs {
+        account: flags.get("--account").cloned(),
+        folder: flags.get("--folder").cloned(),
+        sort: flags
+            .get("--sort")
+            .cloned()
+            .unwrap_or_else(|| "relevance".to_string()),
+        extra_headers: switches.contains("--extra-headers"),
+        terms,
+    })
+}
+
+fn parse_and_run_export(args: &[String]) -> Result<()> {
+    let (args, switches) = take_switches(args, &["--force"]);
+    let (flags, positionals) = parse_args(&args, &["--account", "--folder", "--format"], 1);
+    let account = resolve_account(flags.get("--account").cloned())?;
+    let folder = resolve_folder(
+        &account,
+        Some(
+            flags
+                .get("--folder")
+                .cloned()
+                .context("--folder is required")?,
+        ),
+    )?;
+    let format = flags
+        .get("--format")
         .cloned()
-        .context("--folder is required")?;
-    list_cached_envelopes(EnvelopeListArgs { account, folder })
+        .context("--format is required")?;
+    let dest = positionals
+        .first()
+        .map(PathBuf::from)
+        .context("destination is required")?;
+    run_export(ExportArgs {
+        account: Some(account),
+        folder,
+        format,
+        force: switches.contains("--force"),
+        dest,
+    })
+}
+
+fn parse_and_run_import(args: &[String]) -> Result<()> {
+    let (flags, positionals) = parse_args(args, &["--account", "--folder"], 1);
+    let account = resolve_account(flags.get("--account").cloned())?;
+    let folder = resolve_folder(
+        &account,
+        Some(
+            flags
+                .get("--folder")
+                .cloned()
+                .context("--folder is required")?,
+        ),
+    )?;
+    let path = positionals
+        .first()
+        .map(PathBuf::from)
+        .context("archive path is required")?;
+    run_import(ImportArgs {
+        account: Some(account),
+        folder,
+        path,
+    })
+}
+
+fn parse_and_run_store_migrate(args: &[String]) -> Result<()> {
+    let (args, switches) = take_switches(args, &["--keep"]);
+    let (flags, _) = parse_args(&args, &["--to"], 0);
+    let to = flags.get("--to").cloned().context("--to is required")?;
+    migrate_store(StoreMigrateArgs {
+        to,
+        keep: switches.contains("--keep"),
+    })
+}
+
+fn parse_and_run_store_compress(args: &[String]) -> Result<()> {
+    let (flags, _) = parse_args(args, &["--level"], 0);
+    let level = flags
+        .get("--level")
+        .map(|value| {
+            value
+                .parse::<i32>()
+                .with_context(|| format!("invalid --level {value:?}"))
+        })
+        .transpose()?;
+    compress_store(StoreCompressArgs { level })
 }
 
 fn parse_args(
@@ -267,13 +980,12 @@ fn parse_args(
                 }
             } else {
                 let remaining_non_flags = count_remaining_non_flags(args, index + 1);
-                if remaining_non_flags > required_positionals {
-                    if let Some(value) = args.get(index + 1) {
-                        if !value.starts_with('-') {
-                            index += 2;
-                            continue;
-                        }
-                    }
+                if remaining_non_flags > required_positionals
+                    && let Some(value) = args.get(index + 1)
+                    && !value.starts_with('-')
+                {
+                    index += 2;
+                    continue;
                 }
                 index += 1;
             }
@@ -285,6 +997,32 @@ fn parse_args(
     (flags, positionals)
 }
 
+/// Remove boolean switches from the arguments, returning the remaining
+/// arguments and the switches that were present.
+fn take_switches(args: &[String], known_switches: &[&str]) -> (Vec<String>, HashSet<String>) {
+    let (present, remaining): (Vec<String>, Vec<String>) = args
+        .iter()
+        .cloned()
+        .partition(|token| known_switches.contains(&token.as_str()));
+    (remaining, present.into_iter().collect())
+}
+
+/// Remove every occurrence of a repeatable flag (under any of its names),
+/// returning the remaining arguments and the collected values.
+fn take_flag_values(args: &[String], names: &[&str]) -> (Vec<String>, Vec<String>) {
+    let mut remaining = Vec::new();
+    let mut values = Vec::new();
+    let mut tokens = args.iter();
+    while let Some(token) = tokens.next() {
+        if names.contains(&token.as_str()) {
+            values.extend(tokens.next().cloned());
+        } else {
+            remaining.push(token.clone());
+        }
+    }
+    (remaining, values)
+}
+
 fn count_remaining_non_flags(args: &[String], start: usize) -> usize {
     args.iter()
         .skip(start)
@@ -292,6 +1030,13 @@ fn count_remaining_non_flags(args: &[String], start: usize) -> usize {
         .count()
 }
 
+/// Handle cached account subcommands.
+fn run_account(args: AccountArgs) -> Result<()> {
+    match args.command {
+        AccountCommand::List(args) => list_cached_accounts(args),
+    }
+}
+
 /// Handle cached folder subcommands.
 fn run_folder(args: FolderArgs) -> Result<()> {
     match args.command {
@@ -310,72 +1055,810 @@ fn run_message(args: MessageArgs) -> Result<()> {
 fn run_envelope(args: EnvelopeArgs) -> Result<()> {
     match args.command {
         EnvelopeCommand::List(args) => list_cached_envelopes(args),
+        EnvelopeCommand::Thread(args) => thread_cached_envelopes(args),
     }
 }
 
-/// Print cached folders for the given account.
-fn list_cached_folders(args: FolderListArgs) -> Result<()> {
+/// Handle flag subcommands.
+fn run_flag(args: FlagArgs) -> Result<()> {
+    match args.command {
+        FlagCommand::Add(args) => change_flags("add", args),
+        FlagCommand::Set(args) => change_flags("set", args),
+        FlagCommand::Remove(args) => change_flags("remove", args),
+    }
+}
+
+/// Handle cached attachment subcommands.
+fn run_attachment(args: AttachmentArgs) -> Result<()> {
+    match args.command {
+        AttachmentCommand::List(args) => list_cached_attachments(args),
+        AttachmentCommand::Download(args) => download_cached_attachments(args),
+    }
+}
+
+/// Handle cache storage subcommands.
+fn run_store(args: StoreArgs) -> Result<()> {
+    match args.command {
+        StoreCommand::Migrate(args) => migrate_store(args),
+        StoreCommand::Compress(args) => compress_store(args),
+        StoreCommand::Dedup => dedup_store(),
+    }
+}
+
+fn parse_and_run_serve(args: &[String]) -> Result<()> {
+    let (args, switches) = take_switches(args, &["--stdio", "--socket", "--allow-sync"]);
+    let (flags, _) = parse_args(&args, &["--sync-interval", "--http", "--token"], 0);
+    let sync_interval = flags
+        .get("--sync-interval")
+        .map(|value| {
+            value
+                .parse::<u64>()
+                .with_context(|| format!("invalid --sync-interval {value:?}"))
+        })
+        .transpose()?;
+    run_serve(ServeArgs {
+        stdio: switches.contains("--stdio"),
+        socket: switches.contains("--socket"),
+        http: flags.get("--http").cloned(),
+        token: flags.get("--token").cloned(),
+        allow_sync: switches.contains("--allow-sync"),
+        sync_interval,
+    })
+}
+
+/// Run a long-lived server over the cache, keeping folder and search
+/// indices in memory between requests.
+fn run_serve(args: ServeArgs) -> Result<()> {
+    let modes = [args.stdio, args.socket, args.http.is_some()];
+    if modes.iter().filter(|mode| **mode).count() != 1 {
+        anyhow::bail!("serve needs one of --stdio, --socket or --http");
+    }
+    if args.http.is_none() && (args.token.is_some() || args.allow_sync) {
+        anyhow::bail!("--token and --allow-sync only apply to --http");
+    }
+    if args.sync_interval == Some(0) {
+        anyhow::bail!("--sync-interval must be at least one second");
+    }
+    let sync_interval = args.sync_interval.map(Duration::from_secs);
+    if let Some(address) = &args.http {
+        let access = serve::Access {
+            token: args
+                .token
+                .or_else(|| std::env::var(serve::TOKEN_ENV).ok())
+                .filter(|token| !token.is_empty()),
+            allow_sync: args.allow_sync,
+        };
+        serve::serve_http(cache_dir()?, address, sync_interval, access)
+    } else if args.socket {
+        serve::serve_socket(cache_dir()?, sync_interval)
+    } else {
+        serve::serve_stdio(cache_dir()?, sync_interval)
+    }
+}
+
+/// Handle config subcommands.
+fn run_config(args: ConfigArgs) -> Result<()> {
+    match args.command {
+        ConfigCommand::Show(args) => show_config(args),
+    }
+}
+
+fn parse_and_run_config_show(args: &[String]) -> Result<()> {
+    let (flags, _) = parse_args(args, &["--account", "--folder"], 0);
+    show_config(ConfigShowArgs {
+        account: flags.get("--account").cloned(),
+        folder: flags.get("--folder").cloned(),
+    })
+}
+
+/// Print the effective settings of the cache, an account or a folder.
+fn show_config(args: ConfigShowArgs) -> Result<()> {
+    #[derive(Serialize)]
+    struct Shown<'a> {
+        #[serde(flatten)]
+        settings: Settings,
+        himalaya: Option<himalaya::Info<'a>>,
+    }
+
+    let mut settings = config::get().settings(args.account.as_deref(), args.folder.as_deref())?;
+    settings.cache_dir = profile_dir(settings.cache_dir)?;
+    let shown = Shown {
+        settings,
+        himalaya: himalaya::binary().ok().map(himalaya::Binary::info),
+    };
+    let output = serde_json::to_string_pretty(&shown).context("serialize settings")?;
+    println!("{output}");
+    Ok(())
+}
+
+/// Print the layout migrations the cache needs, running them unless
+/// `--dry-run` is given. Other commands run them on first use anyway.
+fn run_migrate(args: MigrateArgs) -> Result<()> {
     let cache_dir = cache_dir()?;
-    let folders_path = cache_dir
-        .join("folders")
-        .join(format!("{}.json", args.account));
-    let contents = fs::read_to_string(&folders_path)
-        .with_context(|| format!("read {}", folders_path.display()))?;
-    println!("{contents}");
+    let migrations = if args.dry_run {
+        store::migrations::pending(&cache_dir)?
+    } else {
+        let migrations = store::migrations::upgrade(&cache_dir)?;
+        store::mark_changed(&cache_dir)?;
+        migrations
+    };
+    let output = serde_json::to_string_pretty(&migrations).context("serialize migrations")?;
+    println!("{output}");
     Ok(())
 }
 
-/// Print a cached message content for the given account, folder, and id.
-fn read_cached_message(args: MessageReadArgs) -> Result<()> {
+/// Copy the cache into another storage backend, then remove the old files
+/// unless `--keep` is given.
+fn migrate_store(args: StoreMigrateArgs) -> Result<()> {
+    let cache_dir = cache_dir()?;
+    let database = cache_dir.join(store::SQLITE_FILE);
+    let to_sqlite = match args.to.as_str() {
+        "sqlite" => true,
+        "fs" => false,
+        other => anyhow::bail!("unknown store backend {other:?}, expected sqlite or fs"),
+    };
+    if database.exists() == to_sqlite {
+        anyhow::bail!("cache already uses the {} store", args.to);
+    }
+
+    let compression = settings()?.compression();
+    let from = store::open(&cache_dir, compression)?;
+    let messages = if to_sqlite {
+        // Build the database under a temporary name so an interrupted
+        // migration never leaves a partial cache.sqlite behind.
+        let temp = cache_dir.join(format!("{}.tmp", store::SQLITE_FILE));
+        store::SqliteStore::remove(&temp)?;
+        let to = store::SqliteStore::open(&temp, compression)?;
+        let messages = store::copy(from.as_ref(), &to)?;
+        drop(to);
+        fs::rename(&temp, &database)
+            .with_context(|| format!("rename {} to {}", temp.display(), database.display()))?;
+        messages
+    } else {
+        store::copy(from.as_ref(), &store::FsStore::new(&cache_dir, compression))?
+    };
+    drop(from);
+
+    if to_sqlite {
+        if !args.keep {
+            store::FsStore::new(&cache_dir, compression).remove()?;
+        }
+    } else if args.keep {
+        // The database takes precedence while it exists, so set it aside.
+        let backup = cache_dir.join(format!("{}.bak", store::SQLITE_FILE));
+        fs::rename(&database, &backup)
+            .with_context(|| format!("rename {} to {}", database.display(), backup.display()))?;
+    } else {
+        store::SqliteStore::remove(&database)?;
+    }
+    store::mark_changed(&cache_dir)?;
+
+    let summary = format!("{} message(s) migrated to the {} store", messages, args.to);
+    println!(
+        "{}",
+        serde_json::to_string(&summary).context("serialize summary")?
+    );
+    Ok(())
+}
+
+/// Rewrite every cached body zstd-compressed. Bodies cached later follow the
+/// `compression` config setting.
+fn compress_store(args: StoreCompressArgs) -> Result<()> {
+    let level = match args.level {
+        Some(level) => level,
+        None => settings()?.compression_level,
+    };
     let cache_dir = cache_dir()?;
-    let message_path = cache_dir
-        .join("messages")
-        .join(&args.account)
-        .join(&args.folder)
-        .join(format!("{}.eml", args.id));
-    let contents =
-        fs::read(&message_path).with_context(|| format!("read {}", message_path.display()))?;
-    let normalized = String::from_utf8_lossy(&contents).replace("\r\n", "\n");
-    let wrapped = serde_json::to_string(&normalized).context("serialize message")?;
-    let mut stdout = io::stdout();
-    stdout
-        .write_all(wrapped.as_bytes())
+    let store = store::open(&cache_dir, store::Compression::Zstd(level))?;
+    let messages = store::rewrite_messages(store.as_ref())?;
+    store::mark_changed(&cache_dir)?;
+    let summary = format!("{messages} message(s) compressed with zstd level {level}");
+    println!(
+        "{}",
+        serde_json::to_string(&summary).context("serialize summary")?
+    );
+    Ok(())
+}
+
+/// Rewrite every cached body as a shared blob, so bodies cached before
+/// deduplication are stored once.
+fn dedup_store() -> Result<()> {
+    let store = open_store()?;
+    let messages = store::rewrite_messages(store.as_ref())?;
+    store::mark_changed(&cache_dir()?)?;
+    let summary = format!("{messages} message(s) deduplicated");
+    println!(
+        "{}",
+        serde_json::to_string(&summary).context("serialize summary")?
+    );
+    Ok(())
+}
+
+/// Print the cached accounts as JSON or as plain rows of name, backend and
+/// default marker. Before the first sync the list comes from himalaya.
+fn list_cached_accounts(args: AccountListArgs) -> Result<()> {
+    let plain = match args.output.as_str() {
+        "json" => false,
+        "plain" => true,
+        other => anyhow::bail!("unknown output format {other:?}, expected json or plain"),
+    };
+    let Some(accounts) = open_store()?.accounts()? else {
+        return run_himalaya_passthrough(&[
+            "account".to_string(),
+            "list".to_string(),
+            "-o".to_string(),
+            args.output,
+        ]);
+    };
+
+    if plain {
+        for account in &accounts {
+            println!(
+                "{}\t{}\t{}",
+                account.name,
+                account.backend.as_deref().unwrap_or_default(),
+                if account.default == Some(true) {
+                    "default"
+                } else {
+                    ""
+                }
+            );
+        }
+    } else {
+        let output = serde_json::to_string_pretty(&accounts).context("serialize accounts")?;
+        println!("{output}");
+    }
+    Ok(())
+}
+
+/// Print cached folders for the given account.
+fn list_cached_folders(args: FolderListArgs) -> Result<()> {
+    run_cached(CachedCommand::FolderList(args))
+}
+
+/// Read commands a `serve --socket` server answers for its clients.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+enum CachedCommand {
+    FolderList(FolderListArgs),
+    EnvelopeList(EnvelopeListArgs),
+    MessageRead(MessageReadArgs),
+    Search(SearchArgs),
+}
+
+/// Print the output of a read command, forwarded to the server of the cache
+/// when one runs.
+fn run_cached(command: CachedCommand) -> Result<()> {
+    let output = match daemon::forward(&cache_dir()?, &command) {
+        Some(output) => output,
+        None => cached_output(open_store()?.as_ref(), command)?,
+    };
+    io::stdout()
+        .lock()
+        .write_all(output.as_bytes())
+        .context("write to stdout")
+}
+
+/// What a read command prints, computed from `store`.
+fn cached_output(store: &dyn Store, command: CachedCommand) -> Result<String> {
+    let output = match command {
+        CachedCommand::FolderList(args) => {
+            let account = resolve_account(args.account)?;
+            let folders = store
+                .folders(&account)?
+                .with_context(|| format!("folders of account {account} are not cached"))?;
+            serde_json::to_string_pretty(&folders).context("serialize folders")?
+        }
+        CachedCommand::EnvelopeList(args) => {
+            let envelopes = cached_envelopes(store, &args)?;
+            serde_json::to_string_pretty(&envelopes).context("serialize envelopes")?
+        }
+        CachedCommand::MessageRead(args) => {
+            let account = resolve_account(args.account.clone())?;
+            let folder = resolve_folder(&account, args.folder.clone())?;
+            let contents = store.read_message(&account, &folder, &args.id)?;
+            let rendered = render_message(&contents, args)?;
+            // Printed without a trailing newline, like himalaya does.
+            return serde_json::to_string(&rendered).context("serialize message");
+        }
+        CachedCommand::Search(args) => {
+            let hits = search_envelopes(store, args)?;
+            serde_json::to_string_pretty(&hits).context("serialize search results")?
+        }
+    };
+    Ok(output + "\n")
+}
+
+/// Print a cached message for the given account, folder, and id, rendered
+/// like `himalaya message read` and wrapped as a JSON string. With `--raw` or
+/// `--output-file` the original bytes are copied unchanged instead.
+fn read_cached_message(args: MessageReadArgs) -> Result<()> {
+    if !args.raw && args.output_file.is_none() {
+        return run_cached(CachedCommand::MessageRead(args));
+    }
+
+    let account = resolve_account(args.account.clone())?;
+    let folder = resolve_folder(&account, args.folder.clone())?;
+    let store = open_store()?;
+    let contents = store.read_message(&account, &folder, &args.id)?;
+    if let Some(output_file) = &args.output_file {
+        write_bytes(output_file, &contents)?;
+        return Ok(());
+    }
+    io::stdout()
+        .lock()
+        .write_all(&contents)
         .with_context(|| "write message to stdout")?;
     Ok(())
 }
 
-/// Print cached envelopes sorted by date (ascending).
+/// Render a raw message like `himalaya message read`, with the headers
+/// requested by `args`.
+fn render_message(contents: &[u8], args: MessageReadArgs) -> Result<String> {
+    let message = mime::parse(contents).with_context(|| format!("parse message {}", args.id))?;
+    let headers = if args.no_headers {
+        Vec::new()
+    } else if args.headers.is_empty() {
+        mime::DEFAULT_READ_HEADERS
+            .iter()
+            .map(|name| name.to_string())
+            .collect()
+    } else {
+        args.headers
+    };
+    Ok(mime::render(
+        &message,
+        &mime::ReadOptions {
+            headers,
+            html: args.html,
+        },
+    ))
+}
+
+/// Print cached envelopes matching the query, sorted by date (descending) unless
+/// the query has an `order by` clause.
 fn list_cached_envelopes(args: EnvelopeListArgs) -> Result<()> {
-    let cache_dir = cache_dir()?;
-    let meta_dir = cache_dir
-        .join("meta")
-        .join(&args.account)
-        .join(&args.folder);
-
-    let mut envelopes = Vec::new();
-    for entry in fs::read_dir(&meta_dir).with_context(|| format!("read {}", meta_dir.display()))? {
-        let entry = entry.with_context(|| format!("read entry in {}", meta_dir.display()))?;
-        let path = entry.path();
-        if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
-            continue;
+    if args.thread {
+        return thread_cached_envelopes(EnvelopeThreadArgs {
+            account: args.account,
+            folder: args.folder,
+            output: args.output,
+            query: args.query,
+        });
+    }
+
+    run_cached(CachedCommand::EnvelopeList(args))
+}
+
+/// Cached envelopes of a folder matching the query of `args`, sorted and
+/// paginated.
+fn cached_envelopes(store: &dyn Store, args: &EnvelopeListArgs) -> Result<Vec<Envelope>> {
+    let account = resolve_account(args.account.clone())?;
+    if args.page == 0 {
+        anyhow::bail!("pages start at 1");
+    }
+    if args.page_size == Some(0) {
+        anyhow::bail!("pages hold at least one envelope");
+    }
+    let folder = resolve_folder(&account, args.folder.clone())?;
+    let query = Query::parse(&args.query).context("parse envelope query")?;
+    let mut envelopes = store.envelopes(&account, &folder)?;
+    if let Some(filter) = &query.filter {
+        envelopes.retain(|envelope| envelope_matches(store, &account, &folder, filter, envelope));
+    }
+    // The store already returns envelopes newest first, the default order.
+    if !query.sorters.is_empty() {
+        query.sort(&mut envelopes);
+    }
+    if let Some(page_size) = args.page_size {
+        envelopes = envelopes
+            .into_iter()
+            .skip((args.page - 1).saturating_mul(page_size))
+            .take(page_size)
+            .collect();
+    }
+    if !args.extra_headers {
+        envelopes.iter_mut().for_each(Envelope::clear_headers);
+    }
+    Ok(envelopes)
+}
+
+/// Forward a flag change to himalaya, then apply it to the cached envelopes
+/// so listings reflect it without another sync. Numeric arguments are message
+/// ids, the others flag names.
+fn change_flags(action: &str, args: FlagChangeArgs) -> Result<()> {
+    let mut forwarded = vec!["flag".to_string(), action.to_string()];
+    if let Some(folder) = &args.folder {
+        forwarded.extend(["--folder".to_string(), folder.clone()]);
+    }
+    if let Some(account) = &args.account {
+        forwarded.extend(["--account".to_string(), account.clone()]);
+    }
+    forwarded.extend(args.ids_and_flags.iter().cloned());
+    run_himalaya_passthrough(&forwarded)?;
+
+    // Without an account nor a default one the cached folder is unknown;
+    // the next sync picks the change up.
+    let Ok(account) = resolve_account(args.account) else {
+        return Ok(());
+    };
+    let folder = resolve_folder(&account, args.folder)?;
+    let (ids, names): (Vec<String>, Vec<String>) = args
+        .ids_and_flags
+        .into_iter()
+        .partition(|value| value.chars().all(|ch| ch.is_ascii_digit()));
+    let names: Vec<String> = names.iter().map(|name| flag_name(name)).collect();
+
+    let store = open_store()?;
+    let mut updated = store.envelopes(&account, &folder)?;
+    updated.retain(|envelope| ids.contains(&envelope.id));
+    for envelope in &mut updated {
+        let flags = envelope.flags.get_or_insert_with(Vec::new);
+        match action {
+            "set" => *flags = names.clone(),
+            "remove" => {
+                flags.retain(|flag| !names.iter().any(|name| name.eq_ignore_ascii_case(flag)))
+            }
+            _ => {
+                for name in &names {
+                    if !flags.iter().any(|flag| flag.eq_ignore_ascii_case(name)) {
+                        flags.push(name.clone());
+                    }
+                }
+            }
         }
-        let data = fs::read(&path).with_context(|| format!("read {}", path.display()))?;
-        let envelope: Envelope =
-            serde_json::from_slice(&data).with_context(|| format!("parse {}", path.display()))?;
-        envelopes.push(envelope);
+    }
+    store.update_envelopes(&account, &folder, &updated)?;
+    store::mark_changed(&cache_dir()?)
+}
+
+/// Spell standard flags the way himalaya lists them (`seen` becomes `Seen`).
+fn flag_name(name: &str) -> String {
+    const STANDARD: &[&str] = &["Seen", "Answered", "Flagged", "Deleted", "Draft"];
+    STANDARD
+        .iter()
+        .find(|flag| flag.eq_ignore_ascii_case(name))
+        .map_or_else(|| name.to_string(), |flag| flag.to_string())
+}
+
+/// Print the threads of an account as a JSON tree or as indented plain rows.
+fn thread_cached_envelopes(args: EnvelopeThreadArgs) -> Result<()> {
+    let account = resolve_account(args.account.clone())?;
+    let plain = match args.output.as_str() {
+        "json" => false,
+        "plain" => true,
+        other => anyhow::bail!("unknown output format {other:?}, expected json or plain"),
+    };
+    let query = Query::parse(&args.query).context("parse envelope query")?;
+    let folder = args
+        .folder
+        .map(|folder| resolve_folder(&account, Some(folder)))
+        .transpose()?;
+    let store = open_store()?;
+
+    // The requested folder goes first so its copy of duplicated messages wins.
+    let mut folders = store.folder_names(&account)?;
+    if let Some(folder) = &folder {
+        folders.retain(|name| name != folder);
+        folders.insert(0, folder.clone());
+    }
+
+    let mut messages = Vec::new();
+    for folder in folders {
+        let envelopes = store.envelopes(&account, &folder)?;
+        let folder_messages: Vec<threading::ThreadMessage> = envelopes
+            .into_par_iter()
+            .map(|envelope| {
+                let headers = store
+                    .read_message(&account, &folder, &envelope.id)
+                    .map(|bytes| mime::thread_headers(&bytes))
+                    .unwrap_or_default();
+                threading::ThreadMessage {
+                    folder: folder.clone(),
+                    envelope,
+                    headers,
+                }
+            })
+            .collect();
+        messages.extend(folder_messages);
     }
 
-    envelopes.sort_by(|left, right| {
-        let left_date = parse_envelope_date(left);
-        let right_date = parse_envelope_date(right);
-        right_date.cmp(&left_date)
+    let threads = threading::build(&messages, &|message| {
+        folder
+            .as_ref()
+            .is_none_or(|folder| &message.folder == folder)
+            && query.filter.as_ref().is_none_or(|filter| {
+                envelope_matches(
+                    store.as_ref(),
+                    &account,
+                    &message.folder,
+                    filter,
+                    &message.envelope,
+                )
+            })
     });
 
-    let output = serde_json::to_string_pretty(&envelopes).context("serialize envelopes")?;
+    if plain {
+        for node in &threads {
+            print_thread_rows(node, 0);
+        }
+    } else {
+        let output = serde_json::to_string_pretty(&threads).context("serialize threads")?;
+        println!("{output}");
+    }
+    Ok(())
+}
+
+fn print_thread_rows(node: &threading::ThreadNode, depth: usize) {
+    let envelope = node.envelope;
+    let from = envelope
+        .from
+        .as_ref()
+        .and_then(Contacts::first)
+        .and_then(|contact| contact.name.as_deref().or(contact.addr.as_deref()))
+        .unwrap_or_default();
+    println!(
+        "{}\t{}\t{}\t{}\t{}{}",
+        envelope.id,
+        node.folder,
+        envelope.date.as_deref().unwrap_or_default(),
+        from,
+        "  ".repeat(depth),
+        envelope.subject.as_deref().unwrap_or_default()
+    );
+    for child in &node.children {
+        print_thread_rows(child, depth + 1);
+    }
+}
+
+/// Evaluate a query filter against a cached envelope, decoding the message
+/// body only when the filter needs it.
+fn envelope_matches(
+    store: &dyn Store,
+    account: &str,
+    folder: &str,
+    filter: &Filter,
+    envelope: &Envelope,
+) -> bool {
+    let body = OnceCell::new();
+    filter.matches(envelope, &|| {
+        body.get_or_init(|| {
+            store
+                .read_message(account, folder, &envelope.id)
+                .ok()
+                .and_then(|bytes| mime::parse(&bytes).map(|message| mime::body_text(&message)))
+                .unwrap_or_default()
+        })
+        .clone()
+    })
+}
+
+/// Print the attachments of a cached message as JSON.
+fn list_cached_attachments(args: AttachmentListArgs) -> Result<()> {
+    let account = resolve_account(args.account.clone())?;
+    let folder = resolve_folder(&account, args.folder.clone())?;
+    let store = open_store()?;
+    let contents = store.read_message(&account, &folder, &args.id)?;
+    let message = mime::parse(&contents).with_context(|| format!("parse message {}", args.id))?;
+
+    let entries: Vec<AttachmentEntry> = mime::attachments(&message)
+        .into_iter()
+        .map(|attachment| AttachmentEntry {
+            filename: attachment.filename,
+            mime: attachment.mime,
+            size: attachment.contents.len(),
+        })
+        .collect();
+    let output = serde_json::to_string_pretty(&entries).context("serialize attachments")?;
     println!("{output}");
     Ok(())
 }
 
+/// Write the attachments of cached messages to disk, like
+/// `himalaya attachment download`.
+fn download_cached_attachments(args: AttachmentDownloadArgs) -> Result<()> {
+    let account = resolve_account(args.account.clone())?;
+    let folder = resolve_folder(&account, args.folder.clone())?;
+    let store = open_store()?;
+    let dir = match args.dir {
+        Some(dir) => dir,
+        None => UserDirs::new()
+            .and_then(|dirs| dirs.download_dir().map(Path::to_path_buf))
+            .unwrap_or_else(|| PathBuf::from(".")),
+    };
+
+    let mut downloaded = 0;
+    for id in &args.ids {
+        let contents = store.read_message(&account, &folder, id)?;
+        let message = mime::parse(&contents).with_context(|| format!("parse message {id}"))?;
+        let paths = attachment::download(&dir, &mime::attachments(&message))?;
+        for path in &paths {
+            eprintln!("Downloading {}…", path.display());
+        }
+        downloaded += paths.len();
+    }
+
+    let summary = if downloaded == 0 {
+        "No attachment found".to_string()
+    } else {
+        format!(
+            "{} attachment(s) successfully downloaded to {}",
+            downloaded,
+            dir.display()
+        )
+    };
+    println!(
+        "{}",
+        serde_json::to_string(&summary).context("serialize summary")?
+    );
+    Ok(())
+}
+
+/// Print cached envelopes matching the search terms, ranked by relevance or
+/// date.
+fn run_search(args: SearchArgs) -> Result<()> {
+    run_cached(CachedCommand::Search(args))
+}
+
+/// Cached envelopes matching the search terms of `args`, ranked.
+fn search_envelopes(store: &dyn Store, args: SearchArgs) -> Result<Vec<SearchHit>> {
+    let by_date = match args.sort.as_str() {
+        "relevance" => false,
+        "date" => true,
+        other => anyhow::bail!("unknown sort {other:?}, expected relevance or date"),
+    };
+    let folder = match (&args.account, args.folder) {
+        (Some(account), folder @ Some(_)) => Some(resolve_folder(account, folder)?),
+        (_, folder) => folder,
+    };
+    let hits = search::search(
+        store,
+        args.account.as_deref(),
+        folder.as_deref(),
+        &args.terms,
+    )?;
+
+    // Within one folder the location is known, so hits print exactly like
+    // `envelope list`.
+    let located = args.account.is_none() || folder.is_none();
+    let mut results = Vec::new();
+    for hit in hits {
+        if let Some(mut envelope) = store.envelope(&hit.account, &hit.folder, &hit.id)? {
+            if !args.extra_headers {
+                envelope.clear_headers();
+            }
+            results.push(SearchHit {
+                envelope,
+                account: located.then_some(hit.account),
+                folder: located.then_some(hit.folder),
+            });
+        }
+    }
+    if by_date {
+        // Newest first, envelopes without a parsable date last.
+        results.sort_by_key(|hit| std::cmp::Reverse(parse_envelope_date(&hit.envelope)));
+    }
+    Ok(results)
+}
+
+/// Export the cached messages of a folder, oldest first, to mbox or Maildir.
+fn run_export(args: ExportArgs) -> Result<()> {
+    let account = resolve_account(args.account.clone())?;
+    let store = open_store()?;
+    let mut envelopes = store.envelopes(&account, &args.folder)?;
+    envelopes.sort_by_key(parse_envelope_date);
+
+    let mut messages = Vec::new();
+    for envelope in envelopes {
+        match store.read_message(&account, &args.folder, &envelope.id) {
+            Ok(bytes) => messages.push(export::ExportMessage { envelope, bytes }),
+            Err(err) => eprintln!("warning: skipping message {}: {:#}", envelope.id, err),
+        }
+    }
+
+    match args.format.as_str() {
+        "mbox" => export::write_mbox(&args.dest, &messages, args.force)?,
+        "maildir" => export::write_maildir(&args.dest, &messages)?,
+        other => anyhow::bail!("unknown export format {other:?}, expected mbox or maildir"),
+    }
+
+    let summary = format!(
+        "{} message(s) exported to {}",
+        messages.len(),
+        args.dest.display()
+    );
+    println!(
+        "{}",
+        serde_json::to_string(&summary).context("serialize summary")?
+    );
+    Ok(())
+}
+
+/// Import an mbox file or Maildir into a cached folder, synthesizing the
+/// envelope metadata sync would have written. Messages whose Message-ID is
+/// already cached in the folder are skipped.
+fn run_import(args: ImportArgs) -> Result<()> {
+    let account = resolve_account(args.account.clone())?;
+    let store = open_store()?;
+    let archive = import::read_archive(&args.path)?;
+    let imported = import_messages(store.as_ref(), &account, &args.folder, &archive)?;
+    store::mark_changed(&cache_dir()?)?;
+
+    let summary = format!(
+        "{} of {} message(s) imported into {}/{}",
+        imported,
+        archive.len(),
+        account,
+        args.folder
+    );
+    println!(
+        "{}",
+        serde_json::to_string(&summary).context("serialize summary")?
+    );
+    Ok(())
+}
+
+/// Cache archive messages in a folder, returning how many were new. They
+/// get ids of their own, `import-<n>`, which servers never use.
+fn import_messages(
+    store: &dyn Store,
+    account: &str,
+    folder: &str,
+    archive: &[import::ArchiveMessage],
+) -> Result<usize> {
+    let mut envelopes = store.envelopes(account, folder)?;
+    let mut message_ids: HashSet<String> = envelopes
+        .par_iter()
+        .filter_map(|envelope| {
+            let bytes = store.read_message(account, folder, &envelope.id).ok()?;
+            mime::thread_headers(&bytes).message_id
+        })
+        .collect();
+    let mut next_id = envelopes
+        .iter()
+        .filter_map(|envelope| {
+            envelope
+                .id
+                .strip_prefix(IMPORT_ID_PREFIX)?
+                .parse::<u64>()
+                .ok()
+        })
+        .max()
+        .unwrap_or_default()
+        + 1;
+
+    let mut imported = 0;
+    for message in archive {
+        if let Some(message_id) = mime::thread_headers(&message.bytes).message_id
+            && !message_ids.insert(message_id)
+        {
+            continue;
+        }
+        let envelope = import::envelope_for(format!("{IMPORT_ID_PREFIX}{next_id}"), message);
+        next_id += 1;
+
+        store.put_message(account, folder, &envelope.id, &message.bytes)?;
+        envelopes.push(envelope);
+        imported += 1;
+    }
+
+    Query::default().sort(&mut envelopes);
+    store.put_envelopes(account, folder, &envelopes)?;
+
+    let mut folders = store.folders(account)?.unwrap_or_default();
+    if !folders.iter().any(|cached| cached.name == folder) {
+        folders.push(Folder {
+            name: folder.to_string(),
+            desc: None,
+            extra: ExtraFields::new(),
+        });
+        store.put_folders(account, &folders)?;
+    }
+
+    search::update_folder_index(store, account, folder, &envelopes)?;
+    Ok(imported)
+}
+
 fn parse_envelope_date(envelope: &Envelope) -> Option<DateTime<FixedOffset>> {
     envelope
         .date
@@ -385,13 +1868,30 @@ fn parse_envelope_date(envelope: &Envelope) -> Option<DateTime<FixedOffset>> {
 
 /// Perform a cache sync, optionally scoped to account and folder.
 fn run_sync(args: SyncArgs) -> Result<()> {
-    if args.folder.is_some() && args.account.is_none() {
-        anyhow::bail!("--folder requires --account");
+    sync_store(open_store()?.as_ref(), args)?;
+    store::mark_changed(&cache_dir()?)
+}
+
+/// Outcome of syncing one folder.
+#[derive(Debug, Serialize)]
+struct FolderSynced {
+    account: String,
+    folder: String,
+    envelopes: usize,
+    /// Ids that were not cached before, empty on the first sync of a folder.
+    new: Vec<String>,
+}
+
+/// Sync into `store`, returning what each synced folder got.
+fn sync_store(store: &dyn Store, mut args: SyncArgs) -> Result<Vec<FolderSynced>> {
+    if let Some(folder) = args.folder.take() {
+        let account = resolve_account(args.account.take())
+            .context("--folder requires --account or a default account")?;
+        args.folder = Some(resolve_folder(&account, Some(folder))?);
+        args.account = Some(account);
     }
 
-    let cache_dir = cache_dir()?;
-    fs::create_dir_all(&cache_dir)
-        .with_context(|| format!("create cache dir {}", cache_dir.display()))?;
+    let mut synced = Vec::new();
 
     let account_names = match args.account.as_deref() {
         Some(account_name) => vec![account_name.to_string()],
@@ -403,10 +1903,14 @@ fn run_sync(args: SyncArgs) -> Result<()> {
                     return Err(err).context("fetch account list");
                 }
             };
-            let accounts_path = cache_dir.join("accounts.json");
-            write_json(&accounts_path, &accounts)
-                .with_context(|| format!("write {}", accounts_path.display()))?;
-            accounts.into_iter().map(|account| account.name).collect()
+            store.put_accounts(&accounts)?;
+            let mut names = Vec::new();
+            for account in accounts {
+                if config::get().settings(Some(&account.name), None)?.sync {
+                    names.push(account.name);
+                }
+            }
+            names
         }
     };
 
@@ -432,17 +1936,24 @@ fn run_sync(args: SyncArgs) -> Result<()> {
                     }
                 };
 
-                let folders_path = cache_dir
-                    .join("folders")
-                    .join(format!("{}.json", &account_name));
-                write_json(&folders_path, &folders)
-                    .with_context(|| format!("write {}", folders_path.display()))?;
-                folders.into_iter().map(|folder| folder.name).collect()
+                store.put_folders(&account_name, &folders)?;
+                let mut names = Vec::new();
+                for folder in folders {
+                    if config::get()
+                        .settings(Some(&account_name), Some(&folder.name))?
+                        .sync
+                    {
+                        names.push(folder.name);
+                    }
+                }
+                names
             }
         };
 
         for folder_name in folder_names {
-            let envelopes: Vec<Envelope> = match run_himalaya_json(&[
+            let settings = config::get().settings(Some(&account_name), Some(&folder_name))?;
+            let page_size = settings.page_size.to_string();
+            let mut envelopes: Vec<Envelope> = match run_himalaya_json(&[
                 "envelope",
                 "list",
                 "--folder",
@@ -450,7 +1961,7 @@ fn run_sync(args: SyncArgs) -> Result<()> {
                 "--account",
                 &account_name,
                 "--page-size",
-                "999",
+                &page_size,
                 "-o",
                 "json",
             ]) {
@@ -464,14 +1975,39 @@ fn run_sync(args: SyncArgs) -> Result<()> {
                 }
             };
 
-            let envelopes_path = cache_dir
-                .join("envelopes")
-                .join(&account_name)
-                .join(format!("{}.json", &folder_name));
-            write_json(&envelopes_path, &envelopes)
-                .with_context(|| format!("write {}", envelopes_path.display()))?;
+            store::sort_by_date(&mut envelopes);
+            let new = if store.folder_names(&account_name)?.contains(&folder_name) {
+                let cached: HashSet<String> = store
+                    .envelopes(&account_name, &folder_name)?
+                    .into_iter()
+                    .map(|envelope| envelope.id)
+                    .collect();
+                envelopes
+                    .iter()
+                    .filter(|envelope| !cached.contains(&envelope.id))
+                    .map(|envelope| envelope.id.clone())
+                    .collect()
+            } else {
+                Vec::new()
+            };
+            keep_cached_headers(store, &account_name, &folder_name, &mut envelopes);
+            replace_envelopes(store, &account_name, &folder_name, &mut envelopes)?;
+            let copies = cached_copies(store, &account_name, &folder_name, &envelopes)
+                .unwrap_or_else(|err| {
+                    eprintln!(
+                        "warning: failed to look up cached copies for account {} folder {}: {:#}",
+                        account_name, folder_name, err
+                    );
+                    HashMap::new()
+                });
 
-            let progress = ProgressBar::new(envelopes.len() as u64);
+            // Bodies are downloaded newest first, up to `max_bodies`.
+            let wanted = match (settings.bodies, settings.max_bodies) {
+                (false, _) => 0,
+                (true, Some(max)) => max.min(envelopes.len()),
+                (true, None) => envelopes.len(),
+            };
+            let progress = ProgressBar::new(wanted as u64);
             progress.set_style(
                 ProgressStyle::with_template(
                     "{spinner:.green} [{elapsed_precise}] {bar:40.cyan/blue} {pos}/{len} {msg}",
@@ -481,36 +2017,21 @@ fn run_sync(args: SyncArgs) -> Result<()> {
             );
             progress.set_message(format!("{}/{}", account_name, folder_name));
 
-            let progress = progress.clone();
-            let cache_dir = cache_dir.clone();
-            let account_name = account_name.clone();
-            let folder_name = folder_name.clone();
-
-            envelopes.into_par_iter().for_each(|envelope| {
-                let meta_path = cache_dir
-                    .join("meta")
-                    .join(&account_name)
-                    .join(&folder_name)
-                    .join(format!("{}.json", &envelope.id));
-                if let Err(err) = write_json(&meta_path, &envelope)
-                    .with_context(|| format!("write {}", meta_path.display()))
+            envelopes[..wanted].par_iter().for_each(|envelope| {
+                if !store
+                    .has_message(&account_name, &folder_name, &envelope.id)
+                    .unwrap_or(false)
                 {
-                    eprintln!(
-                        "warning: failed to write meta {}: {:#}",
-                        meta_path.display(),
-                        err
-                    );
-                    progress.inc(1);
-                    return;
-                }
-
-                let message_path = cache_dir
-                    .join("messages")
-                    .join(&account_name)
-                    .join(&folder_name)
-                    .join(format!("{}.eml", &envelope.id));
-
-                if !message_path.exists() {
+                    // The same message under another label: store its
+                    // cached body instead of downloading it again.
+                    if let Some(bytes) = cached_copy(store, &account_name, &copies, envelope)
+                        && store
+                            .put_message(&account_name, &folder_name, &envelope.id, &bytes)
+                            .is_ok()
+                    {
+                        progress.inc(1);
+                        return;
+                    }
                     let message_bytes = match run_himalaya_raw(&[
                         "message",
                         "read",
@@ -530,13 +2051,12 @@ fn run_sync(args: SyncArgs) -> Result<()> {
                             return;
                         }
                     };
-                    if let Err(err) = write_bytes(&message_path, &message_bytes)
-                        .with_context(|| format!("write {}", message_path.display()))
+                    if let Err(err) =
+                        store.put_message(&account_name, &folder_name, &envelope.id, &message_bytes)
                     {
                         eprintln!(
-                            "warning: failed to write message {}: {:#}",
-                            message_path.display(),
-                            err
+                            "warning: failed to write message {} for account {} folder {}: {:#}",
+                            envelope.id, account_name, folder_name, err
                         );
                         progress.inc(1);
                         return;
@@ -547,28 +2067,213 @@ fn run_sync(args: SyncArgs) -> Result<()> {
             });
 
             progress.finish_with_message(format!("{}/{} complete", account_name, folder_name));
+
+            if let Err(err) =
+                read_cached_headers(store, &account_name, &folder_name, &mut envelopes)
+            {
+                eprintln!(
+                    "warning: failed to read headers for account {} folder {}: {:#}",
+                    account_name, folder_name, err
+                );
+            }
+
+            if let Err(err) =
+                search::update_folder_index(store, &account_name, &folder_name, &envelopes)
+            {
+                eprintln!(
+                    "warning: failed to index messages for account {} folder {}: {:#}",
+                    account_name, folder_name, err
+                );
+            }
+            synced.push(FolderSynced {
+                envelopes: envelopes.len(),
+                account: account_name.clone(),
+                folder: folder_name,
+                new,
+            });
+        }
+    }
+    Ok(synced)
+}
+
+/// Store the envelopes fetched for a folder in place of the cached ones.
+/// Imported messages exist nowhere else and are kept; the bodies of messages
+/// gone from the server are deleted, as the server may reuse their ids.
+fn replace_envelopes(
+    store: &dyn Store,
+    account: &str,
+    folder: &str,
+    envelopes: &mut Vec<Envelope>,
+) -> Result<()> {
+    let fetched: HashSet<String> = envelopes
+        .iter()
+        .map(|envelope| envelope.id.clone())
+        .collect();
+    let mut deleted = 0;
+    for cached in store.envelopes(account, folder)? {
+        if fetched.contains(&cached.id) {
+            continue;
+        }
+        if cached.id.starts_with(IMPORT_ID_PREFIX) {
+            envelopes.push(cached);
+        } else {
+            store.delete_message(account, folder, &cached.id)?;
+            deleted += 1;
         }
     }
+    store::sort_by_date(envelopes);
+    store.put_envelopes(account, folder, envelopes)?;
+    if deleted > 0 {
+        store.prune_blobs()?;
+    }
     Ok(())
 }
 
-/// Determine the cache root directory.
+/// Carry over the body headers read by an earlier sync, so only new
+/// messages need their body parsed again.
+fn keep_cached_headers(store: &dyn Store, account: &str, folder: &str, envelopes: &mut [Envelope]) {
+    let Ok(cached) = store.envelopes(account, folder) else {
+        return;
+    };
+    let cached: HashMap<&str, &Envelope> = cached
+        .iter()
+        .filter(|envelope| envelope.has_headers())
+        .map(|envelope| (envelope.id.as_str(), envelope))
+        .collect();
+    for envelope in envelopes.iter_mut() {
+        if let Some(previous) = cached.get(envelope.id.as_str()) {
+            envelope.cc = previous.cc.clone();
+            envelope.bcc = previous.bcc.clone();
+            envelope.reply_to = previous.reply_to.clone();
+            envelope.message_id = previous.message_id.clone();
+        }
+    }
+}
+
+/// Fill cc, bcc, reply_to and message_id of the envelopes that lack them
+/// from their cached bodies, and store the updated envelopes.
+fn read_cached_headers(
+    store: &dyn Store,
+    account: &str,
+    folder: &str,
+    envelopes: &mut [Envelope],
+) -> Result<()> {
+    let updated: Vec<Envelope> = envelopes
+        .par_iter_mut()
+        .filter(|envelope| !envelope.has_headers())
+        .filter_map(|envelope| {
+            let bytes = store.read_message(account, folder, &envelope.id).ok()?;
+            envelope.add_headers(mime::envelope_headers(&bytes));
+            Some(envelope.clone())
+        })
+        .collect();
+    if updated.is_empty() {
+        return Ok(());
+    }
+    store.update_envelopes(account, folder, &updated)
+}
+
+/// Message-ID as a lookup key, without angle brackets.
+fn message_id_key(message_id: &str) -> Option<String> {
+    let key = message_id
+        .trim()
+        .trim_start_matches('<')
+        .trim_end_matches('>');
+    (!key.is_empty()).then(|| key.to_string())
+}
+
+/// Messages of other folders of the account with a cached body, by the
+/// Message-ID read from that body, for the Message-IDs the folder listing
+/// reports. himalaya's `envelope list` currently reports none, so every
+/// folder still downloads its copy once (and stores it as a shared blob).
+fn cached_copies(
+    store: &dyn Store,
+    account: &str,
+    folder: &str,
+    envelopes: &[Envelope],
+) -> Result<HashMap<String, Vec<(String, String)>>> {
+    let wanted: HashSet<String> = envelopes
+        .iter()
+        .filter(|envelope| !envelope.has_headers())
+        .filter_map(|envelope| message_id_key(envelope.message_id.as_deref()?))
+        .collect();
+    let mut copies: HashMap<String, Vec<(String, String)>> = HashMap::new();
+    if wanted.is_empty() {
+        return Ok(copies);
+    }
+    for other in store.folder_names(account)? {
+        if other == folder {
+            continue;
+        }
+        for envelope in store.envelopes(account, &other)? {
+            let Some(key) = envelope.message_id.as_deref().and_then(message_id_key) else {
+                continue;
+            };
+            if wanted.contains(&key) && store.has_message(account, &other, &envelope.id)? {
+                copies
+                    .entry(key)
+                    .or_default()
+                    .push((other.clone(), envelope.id));
+            }
+        }
+    }
+    Ok(copies)
+}
+
+/// Cached body of the same message in another folder, when the envelope
+/// listing names its Message-ID. Every cached body with that Message-ID
+/// (and the listed size, if any) must be the same, so a Message-ID reused
+/// for different content is downloaded instead.
+fn cached_copy(
+    store: &dyn Store,
+    account: &str,
+    copies: &HashMap<String, Vec<(String, String)>>,
+    envelope: &Envelope,
+) -> Option<Vec<u8>> {
+    let key = message_id_key(envelope.message_id.as_deref()?)?;
+    let size = envelope
+        .extra
+        .get("size")
+        .and_then(serde_json::Value::as_u64);
+    let mut body: Option<Vec<u8>> = None;
+    for (folder, id) in copies.get(&key)? {
+        let bytes = store.read_message(account, folder, id).ok()?;
+        if size.is_some_and(|size| size != bytes.len() as u64) {
+            continue;
+        }
+        match &body {
+            Some(body) if *body != bytes => return None,
+            Some(_) => {}
+            None => body = Some(bytes),
+        }
+    }
+    body
+}
+
+/// Effective settings of the whole cache.
+fn settings() -> Result<Settings> {
+    config::get().settings(None, None)
+}
+
+/// Determine the cache directory: the configured root, or the namespace of
+/// the himalaya config file given with `-c` or `$HIMALAYA_CONFIG` under its
+/// `profiles` directory. Naming himalaya's usual config keeps the root.
 fn cache_dir() -> Result<PathBuf> {
-    let base_dirs = BaseDirs::new().context("locate home directory")?;
-    Ok(base_dirs
-        .home_dir()
-        .join(".local")
-        .join("share")
-        .join("himalaya-cache"))
+    profile_dir(settings()?.cache_dir)
 }
 
-fn himalaya_path() -> Result<PathBuf> {
-    let base_dirs = BaseDirs::new().context("locate home directory")?;
-    Ok(base_dirs
-        .home_dir()
-        .join(".cargo")
-        .join("bin")
-        .join("himalaya"))
+fn profile_dir(cache_root: PathBuf) -> Result<PathBuf> {
+    match himalaya::chosen_config().filter(|path| !himalaya::is_usual_config(path)) {
+        Some(path) => Ok(cache_root
+            .join("profiles")
+            .join(himalaya::config_id(&path)?)),
+        None => Ok(cache_root),
+    }
+}
+
+/// Open the storage backend of the cache directory.
+fn open_store() -> Result<Box<dyn Store>> {
+    store::open(&cache_dir()?, settings()?.compression())
 }
 
 /// Write JSON to disk, creating parent directories as needed.
@@ -604,11 +2309,21 @@ fn run_himalaya_raw(args: &[&str]) -> Result<Vec<u8>> {
     Ok(output.stdout)
 }
 
-/// Run a himalaya command and retry up to three attempts on failure.
+/// Run a himalaya command, retrying as configured for the account and
+/// folder it targets.
 fn run_himalaya_with_retry(args: &[&str]) -> Result<std::process::Output> {
+    let flag = |name: &str| {
+        args.iter()
+            .position(|arg| *arg == name)
+            .and_then(|index| args.get(index + 1).copied())
+    };
+    let settings = config::get().settings(flag("--account"), flag("--folder"))?;
+    let himalaya = himalaya::binary()?;
+    let attempts = settings.retries.max(1);
     let mut last_error = None;
-    for attempt in 1..=3 {
-        let output = Command::new(himalaya_path()?)
+    for attempt in 1..=attempts {
+        let output = Command::new(&himalaya.path)
+            .args(himalaya::global_args())
             .args(args)
             .output()
             .with_context(|| format!("run himalaya {}", args.join(" ")))?;
@@ -619,8 +2334,8 @@ fn run_himalaya_with_retry(args: &[&str]) -> Result<std::process::Output> {
         let stderr = String::from_utf8_lossy(&output.stderr).to_string();
         last_error = Some(stderr);
 
-        if attempt < 3 {
-            thread::sleep(Duration::from_millis(2500));
+        if attempt < attempts {
+            thread::sleep(Duration::from_millis(settings.retry_delay_ms));
         }
     }
 
@@ -630,3 +2345,149 @@ fn run_himalaya_with_retry(args: &[&str]) -> Result<std::process::Output> {
         stderr.trim()
     ))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Empty cache directory of a test, under the system temp directory.
+    fn temp_cache(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("himalaya-cache-test-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn server_envelope(id: &str, date: &str) -> Envelope {
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "flags": [],
+            "subject": format!("server {id}"),
+            "from": { "name": null, "addr": "server@example.org" },
+            "to": null,
+            "date": date,
+            "has_attachment": false,
+        }))
+        .unwrap()
+    }
+
+    /// Ids of the cached envelopes of a/INBOX, sorted.
+    fn ids(store: &dyn Store) -> Vec<String> {
+        let mut ids: Vec<String> = store
+            .envelopes("a", "INBOX")
+            .unwrap()
+            .into_iter()
+            .map(|envelope| envelope.id)
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn sync_after_import_keeps_imported_messages_apart() {
+        let dir = temp_cache("import-sync");
+        let store = store::FsStore::new(&dir, store::Compression::None);
+        store
+            .put_message("a", "INBOX", "1", b"Subject: server 1\r\n\r\none\r\n")
+            .unwrap();
+        store
+            .put_envelopes(
+                "a",
+                "INBOX",
+                &[server_envelope("1", "2024-01-01 10:00+00:00")],
+            )
+            .unwrap();
+
+        let archive = [import::ArchiveMessage {
+            bytes: b"Message-ID: <old@example.org>\r\nSubject: archived\r\n\r\nold\r\n".to_vec(),
+            flags: Vec::new(),
+        }];
+        assert_eq!(import_messages(&store, "a", "INBOX", &archive).unwrap(), 1);
+        assert_eq!(ids(&store), ["1", "import-1"]);
+
+        // The server deleted message 1 and later gave id 2, then id 1 again,
+        // to new mail.
+        let mut fetched = vec![server_envelope("2", "2024-02-01 10:00+00:00")];
+        replace_envelopes(&store, "a", "INBOX", &mut fetched).unwrap();
+        assert_eq!(ids(&store), ["2", "import-1"]);
+        assert!(!store.has_message("a", "INBOX", "1").unwrap());
+
+        let mut fetched = vec![
+            server_envelope("1", "2024-03-01 10:00+00:00"),
+            server_envelope("2", "2024-02-01 10:00+00:00"),
+        ];
+        replace_envelopes(&store, "a", "INBOX", &mut fetched).unwrap();
+        assert_eq!(ids(&store), ["1", "2", "import-1"]);
+        // Sync downloads the new message 1 instead of reusing the old body.
+        assert!(!store.has_message("a", "INBOX", "1").unwrap());
+        let imported = store.read_message("a", "INBOX", "import-1").unwrap();
+        assert!(String::from_utf8_lossy(&imported).contains("archived"));
+
+        // Importing the archive again adds nothing.
+        assert_eq!(import_messages(&store, "a", "INBOX", &archive).unwrap(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn headers_read_from_a_bare_body_are_not_read_again() {
+        let mut envelope = server_envelope("1", "2024-01-01 10:00+00:00");
+        assert!(!envelope.has_headers());
+        envelope.add_headers(mime::envelope_headers(b"Subject: bare\r\n\r\nbody\r\n"));
+        assert!(envelope.has_headers());
+
+        // The mark survives the round trip through the cache.
+        let cached: Envelope =
+            serde_json::from_str(&serde_json::to_string(&envelope).unwrap()).unwrap();
+        assert!(cached.has_headers());
+        envelope.clear_headers();
+        assert!(!serde_json::to_string(&envelope).unwrap().contains("cc"));
+    }
+
+    #[test]
+    fn bodies_cached_in_other_folders_are_found_by_message_id() {
+        let dir = temp_cache("cached-copies");
+        let store = store::FsStore::new(&dir, store::Compression::None);
+        let body = |id: &str, text: &str| format!("Message-ID: <{id}>\r\n\r\n{text}\r\n");
+        for (folder, id, message_id, text) in [
+            ("INBOX", "1", "one@example.org", "one"),
+            ("Archive", "7", "one@example.org", "one"),
+            ("INBOX", "2", "two@example.org", "two"),
+            ("Drafts", "3", "two@example.org", "two, edited"),
+        ] {
+            let bytes = body(message_id, text);
+            let mut envelope = server_envelope(id, "2024-01-01 10:00+00:00");
+            envelope.add_headers(mime::envelope_headers(bytes.as_bytes()));
+            store.put_envelopes("a", folder, &[envelope]).unwrap();
+            store
+                .put_message("a", folder, id, bytes.as_bytes())
+                .unwrap();
+        }
+
+        let listed = |id: &str, message_id: Option<&str>, size: Option<usize>| {
+            let mut envelope = server_envelope(id, "2024-01-01 10:00+00:00");
+            envelope.message_id = message_id.map(str::to_string);
+            if let Some(size) = size {
+                envelope.extra.insert("size".to_string(), size.into());
+            }
+            envelope
+        };
+        let one = body("one@example.org", "one");
+        let two = body("two@example.org", "two");
+        let envelopes = [
+            listed("1", Some("<one@example.org>"), None),
+            listed("2", Some("two@example.org"), None),
+            listed("3", Some("<two@example.org>"), Some(two.len())),
+            listed("4", None, None),
+        ];
+        let copies = cached_copies(&store, "a", "Labels", &envelopes).unwrap();
+        let copy = |envelope: &Envelope| cached_copy(&store, "a", &copies, envelope);
+
+        assert_eq!(copy(&envelopes[0]), Some(one.into_bytes()));
+        // Two different bodies claim the Message-ID, unless the listed size
+        // tells them apart.
+        assert_eq!(copy(&envelopes[1]), None);
+        assert_eq!(copy(&envelopes[2]), Some(two.into_bytes()));
+        assert_eq!(copy(&envelopes[3]), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,307 @@
+//! MIME decoding of cached raw messages.
+
+use mail_parser::{
+    Address, Header, HeaderValue, Message, MessageParser, MimeHeaders,
+    decoders::charsets::map::charset_decoder,
+};
+
+use crate::{Contact, ExtraFields};
+
+/// Headers shown above the body when none are requested, as in himalaya.
+pub const DEFAULT_READ_HEADERS: &[&str] = &["From", "To", "Cc", "Subject"];
+
+/// How to render a message for `message read`.
+pub struct ReadOptions {
+    /// Header names to show above the body, in order.
+    pub headers: Vec<String>,
+    /// Show the text/html parts instead of the text/plain ones.
+    pub html: bool,
+}
+
+/// Parse a raw message, decoding transfer encodings and charsets.
+pub fn parse(bytes: &[u8]) -> Option<Message<'_>> {
+    MessageParser::default().parse(bytes)
+}
+
+/// Decoded text of a message used for full-text search: every text part
+/// (HTML converted to text) plus the Cc and Reply-To addresses.
+pub fn searchable_text(message: &Message) -> String {
+    let mut text = String::new();
+    for address in [message.cc(), message.reply_to()].into_iter().flatten() {
+        push_addresses(&mut text, address);
+    }
+    text.push('\n');
+    text.push_str(&body_text(message));
+    text
+}
+
+/// Decoded text parts of a message, with HTML-only parts converted to text.
+pub fn body_text(message: &Message) -> String {
+    (0..message.text_body_count())
+        .filter_map(|index| message.body_text(index))
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// Render a message the way `himalaya message read` does: the selected
+/// headers, a blank line, then the decoded body. Text parts are preferred and
+/// HTML-only messages are converted to text, unless `html` is set.
+pub fn render(message: &Message, options: &ReadOptions) -> String {
+    let mut output = String::new();
+    for name in &options.headers {
+        if let Some((name, value)) = header_display(message, name) {
+            output.push_str(&format!("{name}: {value}\n"));
+        }
+    }
+    if !output.is_empty() {
+        output.push('\n');
+    }
+
+    let body = if options.html {
+        (0..message.html_body_count())
+            .filter_map(|index| message.body_html(index))
+            .collect::<Vec<_>>()
+            .join("\n\n")
+    } else {
+        body_text(message)
+    };
+    output.push_str(&body.replace("\r\n", "\n"));
+    output
+}
+
+/// Name and decoded value of the first header with the given name, if
+/// present. Message identifiers are shown raw so they keep their brackets.
+fn header_display<'a>(message: &'a Message, name: &str) -> Option<(&'a str, String)> {
+    let header = message
+        .headers()
+        .iter()
+        .find(|header| header.name().eq_ignore_ascii_case(name))?;
+    if let Some(value) = decode_8bit_header(message, header) {
+        return Some((header.name(), value));
+    }
+    let value = match header.value() {
+        HeaderValue::Address(address) => format_addresses(address),
+        HeaderValue::DateTime(date) => date.to_rfc822(),
+        HeaderValue::Text(text) if !is_id_header(header.name()) => text.to_string(),
+        _ => message.header_raw(header.name())?.trim().to_string(),
+    };
+    Some((header.name(), value))
+}
+
+/// Decode a header holding raw 8-bit bytes with the charset declared by the
+/// message, instead of the lossy UTF-8 the parser falls back to.
+fn decode_8bit_header(message: &Message, header: &Header) -> Option<String> {
+    let raw = message
+        .raw_message()
+        .get(header.offset_start() as usize..header.offset_end() as usize)?;
+    if std::str::from_utf8(raw).is_ok() {
+        return None;
+    }
+    let charset = message
+        .parts
+        .iter()
+        .find_map(|part| part.content_type()?.attribute("charset"))?;
+    let decoder = charset_decoder(charset.as_bytes())?;
+    Some(
+        decoder(raw)
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" "),
+    )
+}
+
+fn is_id_header(name: &str) -> bool {
+    ["Message-ID", "In-Reply-To", "References"]
+        .iter()
+        .any(|id_header| id_header.eq_ignore_ascii_case(name))
+}
+
+/// Format addresses as `Name <addr>`, comma separated.
+fn format_addresses(address: &Address) -> String {
+    address
+        .iter()
+        .map(|addr| match (addr.name(), addr.address()) {
+            (Some(name), Some(email)) => format!("{name} <{email}>"),
+            (None, Some(email)) => email.to_string(),
+            (Some(name), None) => name.to_string(),
+            (None, None) => String::new(),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn push_addresses(text: &mut String, address: &Address) {
+    for addr in address.iter() {
+        for value in [addr.name(), addr.address()].into_iter().flatten() {
+            text.push(' ');
+            text.push_str(value);
+        }
+    }
+}
+
+/// Attachment of a cached message, with its decoded contents.
+pub struct Attachment<'a> {
+    pub filename: Option<String>,
+    pub mime: String,
+    pub contents: &'a [u8],
+}
+
+/// Attachments of a message, in the order they appear in the MIME tree.
+pub fn attachments<'a>(message: &'a Message) -> Vec<Attachment<'a>> {
+    message
+        .attachments()
+        .map(|part| {
+            let mime = part
+                .content_type()
+                .map(|content_type| match content_type.subtype() {
+                    Some(subtype) => format!("{}/{}", content_type.ctype(), subtype),
+                    None => content_type.ctype().to_string(),
+                })
+                .unwrap_or_else(|| "application/octet-stream".to_string());
+            Attachment {
+                filename: part.attachment_name().map(str::to_string),
+                mime: mime.to_lowercase(),
+                contents: part.contents(),
+            }
+        })
+        .collect()
+}
+
+/// Headers used to build conversation threads.
+#[derive(Debug, Default)]
+pub struct ThreadHeaders {
+    pub message_id: Option<String>,
+    pub in_reply_to: Vec<String>,
+    pub references: Vec<String>,
+}
+
+/// Read the threading headers of a raw message without decoding its body.
+pub fn thread_headers(bytes: &[u8]) -> ThreadHeaders {
+    let Some(message) = MessageParser::default().parse_headers(bytes) else {
+        return ThreadHeaders::default();
+    };
+    ThreadHeaders {
+        message_id: message.message_id().map(str::to_string),
+        in_reply_to: header_ids(message.in_reply_to()),
+        references: header_ids(message.references()),
+    }
+}
+
+fn header_ids(value: &HeaderValue) -> Vec<String> {
+    match value {
+        HeaderValue::Text(id) => vec![id.to_string()],
+        HeaderValue::TextList(ids) => ids.iter().map(|id| id.to_string()).collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Address and identity headers that himalaya leaves out of envelopes.
+#[derive(Debug, Default)]
+pub struct EnvelopeHeaders {
+    pub cc: Vec<Contact>,
+    pub bcc: Vec<Contact>,
+    pub reply_to: Vec<Contact>,
+    pub message_id: Option<String>,
+}
+
+/// Read the envelope headers of a raw message without decoding its body.
+pub fn envelope_headers(bytes: &[u8]) -> EnvelopeHeaders {
+    let Some(message) = MessageParser::default().parse_headers(bytes) else {
+        return EnvelopeHeaders::default();
+    };
+    EnvelopeHeaders {
+        cc: contacts(message.cc()),
+        bcc: contacts(message.bcc()),
+        reply_to: contacts(message.reply_to()),
+        message_id: message.message_id().map(str::to_string),
+    }
+}
+
+/// Every mailbox of an address header, with groups flattened.
+pub fn contacts(address: Option<&Address>) -> Vec<Contact> {
+    address
+        .into_iter()
+        .flat_map(Address::iter)
+        .map(|addr| Contact {
+            name: addr.name().map(str::to_string),
+            addr: addr.address().map(str::to_string),
+            extra: ExtraFields::new(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(raw: &str, headers: &[&str], html: bool) -> String {
+        let message = parse(raw.as_bytes()).unwrap();
+        render(
+            &message,
+            &ReadOptions {
+                headers: headers.iter().map(|name| name.to_string()).collect(),
+                html,
+            },
+        )
+    }
+
+    const ALTERNATIVE: &str = "From: =?UTF-8?Q?Ren=C3=A9_Dupont?= <rene@example.org>\r\n\
+        To: =?ISO-8859-1?B?Suly9G1l?= <jerome@example.org>\r\n\
+        Subject: =?UTF-8?B?Q2Fmw6kgw6AgbWlkaQ==?=\r\n\
+        Message-ID: <a1@example.org>\r\n\
+        Content-Type: multipart/alternative; boundary=b\r\n\
+        \r\n\
+        --b\r\n\
+        Content-Type: text/plain; charset=utf-8\r\n\
+        Content-Transfer-Encoding: quoted-printable\r\n\
+        \r\n\
+        Plain caf=C3=A9\r\n\
+        --b\r\n\
+        Content-Type: text/html; charset=utf-8\r\n\
+        \r\n\
+        <p>Html café</p>\r\n\
+        --b--\r\n";
+
+    #[test]
+    fn encoded_words_are_decoded() {
+        let output = read(ALTERNATIVE, DEFAULT_READ_HEADERS, false);
+        assert!(output.starts_with(
+            "From: René Dupont <rene@example.org>\nTo: Jérôme <jerome@example.org>\n\
+             Subject: Café à midi\n\n"
+        ));
+    }
+
+    #[test]
+    fn parts_follow_the_requested_kind() {
+        assert_eq!(read(ALTERNATIVE, &[], false), "Plain café");
+        assert_eq!(read(ALTERNATIVE, &[], true), "<p>Html café</p>");
+
+        let html_only = "Subject: x\r\nContent-Type: text/html\r\n\r\n<p>Only <b>html</b></p>\r\n";
+        let text = read(html_only, &[], false);
+        assert!(text.contains("Only html") && !text.contains('<'), "{text}");
+    }
+
+    #[test]
+    fn headers_keep_the_requested_order_and_raw_ids() {
+        let output = read(ALTERNATIVE, &["subject", "Message-ID", "X-Missing"], false);
+        assert!(output.starts_with("Subject: Café à midi\nMessage-ID: <a1@example.org>\n\n"));
+    }
+
+    #[test]
+    fn eight_bit_headers_use_the_declared_charset() {
+        let raw = b"From: Ren\xe9 <rene@example.org>\r\nSubject: Caf\xe9  cr\xe8me\r\n\
+            Content-Type: text/plain; charset=iso-8859-1\r\n\r\nCaf\xe9\r\n";
+        let message = parse(raw).unwrap();
+        let output = render(
+            &message,
+            &ReadOptions {
+                headers: vec!["From".to_string(), "Subject".to_string()],
+                html: false,
+            },
+        );
+        assert_eq!(
+            output,
+            "From: René <rene@example.org>\nSubject: Café crème\n\nCafé\n"
+        );
+    }
+}
@@ -0,0 +1,543 @@
+//! Parser and evaluator for the himalaya envelope query language.
+//!
+//! The grammar mirrors `himalaya envelope list [QUERY]...`:
+//!
+//! ```text
+//! query  = filter | filter "order by" sorters | "order by" sorters
+//! filter = filter "or" filter | filter "and" filter | "not" filter
+//!        | "(" filter ")" | condition
+//! condition = ("subject" | "from" | "to" | "body") pattern
+//!           | "flag" flag
+//!           | ("date" | "before" | "after") date
+//! sorters = sorter+
+//! sorter  = ("date" | "from" | "to" | "subject") ["asc" | "desc"]
+//! ```
+//!
+//! `not` binds tighter than `and`, which binds tighter than `or`.
+
+use anyhow::{Context, Result, bail};
+use chrono::NaiveDate;
+use std::cmp::Ordering;
+
+use crate::{Contacts, Envelope, parse_envelope_date};
+
+/// Parsed envelope query: an optional filter and a list of sorters.
+#[derive(Debug, Default)]
+pub struct Query {
+    pub filter: Option<Filter>,
+    pub sorters: Vec<Sorter>,
+}
+
+/// Filter condition evaluated against a cached envelope.
+#[derive(Debug)]
+pub enum Filter {
+    And(Box<Filter>, Box<Filter>),
+    Or(Box<Filter>, Box<Filter>),
+    Not(Box<Filter>),
+    Subject(String),
+    From(String),
+    To(String),
+    Body(String),
+    Flag(String),
+    Date(NaiveDate),
+    Before(NaiveDate),
+    After(NaiveDate),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum SortKind {
+    Date,
+    From,
+    To,
+    Subject,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Sorter {
+    pub kind: SortKind,
+    pub order: SortOrder,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Quoted(String),
+    Open,
+    Close,
+}
+
+impl Query {
+    /// Parse query arguments as given on the command line. An argument
+    /// holding whitespace was quoted in the shell and is one value.
+    pub fn parse(args: &[String]) -> Result<Self> {
+        let mut tokens = Vec::new();
+        for arg in args {
+            if !arg.contains(char::is_whitespace) {
+                tokens.extend(tokenize(arg)?);
+                continue;
+            }
+            match tokenize(arg).ok().as_deref() {
+                Some([quoted @ Token::Quoted(_)]) => tokens.push(quoted.clone()),
+                _ => tokens.push(Token::Quoted(arg.clone())),
+            }
+        }
+        let mut parser = Parser { tokens, index: 0 };
+        parser.parse_query()
+    }
+
+    /// Sort envelopes by the query sorters, or by date descending by default.
+    pub fn sort(&self, envelopes: &mut [Envelope]) {
+        if self.sorters.is_empty() {
+            envelopes.sort_by(|left, right| compare(SortKind::Date, left, right).reverse());
+            return;
+        }
+        envelopes.sort_by(|left, right| {
+            self.sorters
+                .iter()
+                .map(|sorter| {
+                    let ordering = compare(sorter.kind, left, right);
+                    match sorter.order {
+                        SortOrder::Asc => ordering,
+                        SortOrder::Desc => ordering.reverse(),
+                    }
+                })
+                .find(|ordering| ordering.is_ne())
+                .unwrap_or(Ordering::Equal)
+        });
+    }
+}
+
+impl Filter {
+    /// Evaluate the filter; `body` is only called for `body` conditions.
+    pub fn matches(&self, envelope: &Envelope, body: &dyn Fn() -> String) -> bool {
+        match self {
+            Filter::And(left, right) => {
+                left.matches(envelope, body) && right.matches(envelope, body)
+            }
+            Filter::Or(left, right) => {
+                left.matches(envelope, body) || right.matches(envelope, body)
+            }
+            Filter::Not(inner) => !inner.matches(envelope, body),
+            Filter::Subject(pattern) => envelope
+                .subject
+                .as_deref()
+                .is_some_and(|subject| contains(subject, pattern)),
+            Filter::From(pattern) => contact_matches(envelope.from.as_ref(), pattern),
+            Filter::To(pattern) => contact_matches(envelope.to.as_ref(), pattern),
+            Filter::Body(pattern) => contains(&body(), pattern),
+            Filter::Flag(flag) => envelope
+                .flags
+                .iter()
+                .flatten()
+                .any(|value| value.eq_ignore_ascii_case(flag)),
+            Filter::Date(date) => envelope_day(envelope).is_some_and(|day| day == *date),
+            Filter::Before(date) => envelope_day(envelope).is_some_and(|day| day < *date),
+            Filter::After(date) => envelope_day(envelope).is_some_and(|day| day > *date),
+        }
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    index: usize,
+}
+
+impl Parser {
+    fn parse_query(&mut self) -> Result<Query> {
+        let mut query = Query::default();
+        if self.peek().is_none() {
+            return Ok(query);
+        }
+        if !self.at_order_by() {
+            query.filter = Some(self.parse_or()?);
+        }
+        if self.at_order_by() {
+            self.index += 2;
+            query.sorters = self.parse_sorters()?;
+        }
+        if let Some(token) = self.peek() {
+            bail!("unexpected token {} in query", describe(token));
+        }
+        Ok(query)
+    }
+
+    fn parse_or(&mut self) -> Result<Filter> {
+        let mut filter = self.parse_and()?;
+        while self.eat_keyword("or") {
+            let right = self.parse_and()?;
+            filter = Filter::Or(Box::new(filter), Box::new(right));
+        }
+        Ok(filter)
+    }
+
+    fn parse_and(&mut self) -> Result<Filter> {
+        let mut filter = self.parse_not()?;
+        while self.eat_keyword("and") {
+            let right = self.parse_not()?;
+            filter = Filter::And(Box::new(filter), Box::new(right));
+        }
+        Ok(filter)
+    }
+
+    fn parse_not(&mut self) -> Result<Filter> {
+        if self.eat_keyword("not") {
+            let inner = self.parse_not()?;
+            return Ok(Filter::Not(Box::new(inner)));
+        }
+        self.parse_condition()
+    }
+
+    fn parse_condition(&mut self) -> Result<Filter> {
+        match self.next() {
+            Some(Token::Open) => {
+                let filter = self.parse_or()?;
+                match self.next() {
+                    Some(Token::Close) => Ok(filter),
+                    _ => bail!("missing closing parenthesis in query"),
+                }
+            }
+            Some(Token::Word(word)) => match word.to_lowercase().as_str() {
+                "subject" => Ok(Filter::Subject(self.expect_value("subject")?)),
+                "from" => Ok(Filter::From(self.expect_value("from")?)),
+                "to" => Ok(Filter::To(self.expect_value("to")?)),
+                "body" => Ok(Filter::Body(self.expect_value("body")?)),
+                "flag" => Ok(Filter::Flag(self.expect_value("flag")?)),
+                "date" => Ok(Filter::Date(parse_date(&self.expect_value("date")?)?)),
+                "before" => Ok(Filter::Before(parse_date(&self.expect_value("before")?)?)),
+                "after" => Ok(Filter::After(parse_date(&self.expect_value("after")?)?)),
+                _ => bail!("unknown query condition {word:?}"),
+            },
+            Some(token) => bail!("unexpected token {} in query", describe(&token)),
+            None => bail!("unexpected end of query"),
+        }
+    }
+
+    fn parse_sorters(&mut self) -> Result<Vec<Sorter>> {
+        let mut sorters = Vec::new();
+        while let Some(Token::Word(word)) = self.peek() {
+            let kind = match word.to_lowercase().as_str() {
+                "date" => SortKind::Date,
+                "from" => SortKind::From,
+                "to" => SortKind::To,
+                "subject" => SortKind::Subject,
+                _ => bail!("unknown sort key {word:?}"),
+            };
+            self.index += 1;
+            let order = if self.eat_keyword("desc") {
+                SortOrder::Desc
+            } else {
+                self.eat_keyword("asc");
+                SortOrder::Asc
+            };
+            sorters.push(Sorter { kind, order });
+        }
+        if sorters.is_empty() {
+            bail!("order by requires at least one sort key");
+        }
+        Ok(sorters)
+    }
+
+    fn expect_value(&mut self, condition: &str) -> Result<String> {
+        match self.next() {
+            Some(Token::Word(value)) | Some(Token::Quoted(value)) => Ok(value),
+            _ => bail!("{condition} requires a value"),
+        }
+    }
+
+    fn at_order_by(&self) -> bool {
+        let is_keyword = |offset: usize, keyword: &str| {
+            matches!(
+                self.tokens.get(self.index + offset),
+                Some(Token::Word(word)) if word.eq_ignore_ascii_case(keyword)
+            )
+        };
+        is_keyword(0, "order") && is_keyword(1, "by")
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        match self.peek() {
+            Some(Token::Word(word)) if word.eq_ignore_ascii_case(keyword) => {
+                self.index += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.index)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.index).cloned();
+        self.index += 1;
+        token
+    }
+}
+
+/// Split a query written on one line, as sent over `serve`, into the
+/// arguments a shell would pass, keeping quoted values quoted.
+pub fn split(line: &str) -> Result<Vec<String>> {
+    Ok(tokenize(line)?
+        .into_iter()
+        .map(|token| match token {
+            Token::Word(word) => word,
+            Token::Quoted(value) => {
+                format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+            }
+            Token::Open => "(".to_string(),
+            Token::Close => ")".to_string(),
+        })
+        .collect())
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = input.chars().peekable();
+    while let Some(&ch) = chars.peek() {
+        match ch {
+            ch if ch.is_whitespace() => {
+                chars.next();
+            }
+            '(' => {
+                chars.next();
+                tokens.push(Token::Open);
+            }
+            ')' => {
+                chars.next();
+                tokens.push(Token::Close);
+            }
+            '"' => {
+                chars.next();
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => value.extend(chars.next()),
+                        Some(ch) => value.push(ch),
+                        None => bail!("unterminated quoted string in query"),
+                    }
+                }
+                tokens.push(Token::Quoted(value));
+            }
+            _ => {
+                let mut value = String::new();
+                while let Some(&ch) = chars.peek() {
+                    if ch.is_whitespace() || ch == '(' || ch == ')' {
+                        break;
+                    }
+                    value.push(ch);
+                    chars.next();
+                }
+                tokens.push(Token::Word(value));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+fn describe(token: &Token) -> String {
+    match token {
+        Token::Word(word) => format!("{word:?}"),
+        Token::Quoted(value) => format!("\"{value}\""),
+        Token::Open => "\"(\"".to_string(),
+        Token::Close => "\")\"".to_string(),
+    }
+}
+
+fn parse_date(value: &str) -> Result<NaiveDate> {
+    ["%Y-%m-%d", "%Y/%m/%d", "%d-%m-%Y", "%d/%m/%Y"]
+        .iter()
+        .find_map(|format| NaiveDate::parse_from_str(value, format).ok())
+        .with_context(|| format!("invalid date {value:?}, expected yyyy-mm-dd"))
+}
+
+fn envelope_day(envelope: &Envelope) -> Option<NaiveDate> {
+    parse_envelope_date(envelope).map(|date| date.date_naive())
+}
+
+fn contains(haystack: &str, pattern: &str) -> bool {
+    haystack.to_lowercase().contains(&pattern.to_lowercase())
+}
+
+fn contact_matches(contacts: Option<&Contacts>, pattern: &str) -> bool {
+    contacts
+        .into_iter()
+        .flat_map(Contacts::iter)
+        .any(|contact| {
+            contact
+                .name
+                .as_deref()
+                .is_some_and(|name| contains(name, pattern))
+                || contact
+                    .addr
+                    .as_deref()
+                    .is_some_and(|addr| contains(addr, pattern))
+        })
+}
+
+fn contact_key(contacts: Option<&Contacts>) -> String {
+    contacts
+        .and_then(Contacts::first)
+        .and_then(|contact| contact.name.as_deref().or(contact.addr.as_deref()))
+        .unwrap_or_default()
+        .to_lowercase()
+}
+
+fn compare(kind: SortKind, left: &Envelope, right: &Envelope) -> Ordering {
+    match kind {
+        SortKind::Date => parse_envelope_date(left).cmp(&parse_envelope_date(right)),
+        SortKind::From => contact_key(left.from.as_ref()).cmp(&contact_key(right.from.as_ref())),
+        SortKind::To => contact_key(left.to.as_ref()).cmp(&contact_key(right.to.as_ref())),
+        SortKind::Subject => {
+            let key = |envelope: &Envelope| {
+                envelope
+                    .subject
+                    .as_deref()
+                    .unwrap_or_default()
+                    .to_lowercase()
+            };
+            key(left).cmp(&key(right))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(query: &str) -> Result<Query> {
+        Query::parse(&split(query)?)
+    }
+
+    fn filter(query: &str) -> Filter {
+        parse(query).unwrap().filter.unwrap()
+    }
+
+    fn envelope(date: &str) -> Envelope {
+        serde_json::from_value(serde_json::json!({
+            "id": "1",
+            "flags": ["Seen"],
+            "subject": "Hello world",
+            "from": { "name": "Alice", "addr": "alice@example.org" },
+            "to": null,
+            "date": date,
+            "has_attachment": false,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        let filter = filter("subject a or subject b and not flag seen");
+        let Filter::Or(left, right) = filter else {
+            panic!("expected or, got {filter:?}");
+        };
+        assert!(matches!(*left, Filter::Subject(ref value) if value == "a"));
+        let Filter::And(left, right) = *right else {
+            panic!("expected and, got {right:?}");
+        };
+        assert!(matches!(*left, Filter::Subject(ref value) if value == "b"));
+        assert!(matches!(*right, Filter::Not(ref inner) if matches!(**inner, Filter::Flag(_))));
+    }
+
+    #[test]
+    fn parentheses_group() {
+        let filter = filter("(subject a or subject b) and flag seen");
+        let Filter::And(left, right) = filter else {
+            panic!("expected and, got {filter:?}");
+        };
+        assert!(matches!(*left, Filter::Or(..)));
+        assert!(matches!(*right, Filter::Flag(ref flag) if flag == "seen"));
+        assert!(parse("(subject a").is_err());
+        assert!(parse("subject a)").is_err());
+    }
+
+    #[test]
+    fn quoted_values_keep_spaces_and_keywords() {
+        assert!(matches!(
+            filter(r#"subject "and or (not)""#),
+            Filter::Subject(ref value) if value == "and or (not)"
+        ));
+        assert!(matches!(
+            filter(r#"from "say \"hi\"""#),
+            Filter::From(ref value) if value == r#"say "hi""#
+        ));
+        assert!(parse(r#"subject "open"#).is_err());
+    }
+
+    #[test]
+    fn shell_arguments_keep_their_spaces() {
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        let query = Query::parse(&args(&[
+            "subject",
+            "weekly report",
+            "and",
+            "not",
+            "flag",
+            "seen",
+        ]))
+        .unwrap();
+        let Some(Filter::And(left, _)) = query.filter else {
+            panic!("expected and, got {:?}", query.filter);
+        };
+        assert!(matches!(*left, Filter::Subject(ref value) if value == "weekly report"));
+        let query = Query::parse(&args(&["(subject", "a", "or", "from", "b)"])).unwrap();
+        assert!(matches!(query.filter, Some(Filter::Or(..))));
+        assert!(matches!(
+            Query::parse(&args(&["subject", r#""a b""#])).unwrap().filter,
+            Some(Filter::Subject(ref value)) if value == "a b"
+        ));
+    }
+
+    #[test]
+    fn unknown_keywords_are_errors() {
+        let err = parse("size 10").unwrap_err().to_string();
+        assert!(err.contains("unknown query condition"), "{err}");
+        let err = parse("order by size").unwrap_err().to_string();
+        assert!(err.contains("unknown sort key"), "{err}");
+        assert!(parse("order by").is_err());
+        assert!(parse("subject").is_err());
+        assert!(parse("subject a subject b").is_err());
+    }
+
+    #[test]
+    fn order_by_sorters() {
+        let query = parse("flag seen order by subject desc date").unwrap();
+        assert!(query.filter.is_some());
+        assert!(matches!(
+            query.sorters[..],
+            [
+                Sorter {
+                    kind: SortKind::Subject,
+                    order: SortOrder::Desc
+                },
+                Sorter {
+                    kind: SortKind::Date,
+                    order: SortOrder::Asc
+                },
+            ]
+        ));
+        assert!(parse("order by date").unwrap().filter.is_none());
+    }
+
+    #[test]
+    fn date_bounds_are_exclusive() {
+        let day = envelope("2024-01-02 23:30+01:00");
+        let no_body = || String::new();
+        assert!(filter("date 2024-01-02").matches(&day, &no_body));
+        assert!(!filter("before 2024-01-02").matches(&day, &no_body));
+        assert!(filter("before 2024-01-03").matches(&day, &no_body));
+        assert!(!filter("after 2024-01-02").matches(&day, &no_body));
+        assert!(filter("after 2024-01-01").matches(&day, &no_body));
+        assert!(filter("date 02/01/2024").matches(&day, &no_body));
+        assert!(!filter("after 2000-01-01").matches(&envelope("yesterday"), &no_body));
+        assert!(parse("before 2024-13-01").is_err());
+    }
+}
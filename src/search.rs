@@ -0,0 +1,227 @@
+//! Full-text inverted index over cached messages.
+//!
+//! Each folder gets its own index, kept in the cache store and built
+//! incrementally during sync from decoded text parts, subjects and addresses.
+
+use anyhow::{Context, Result};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::{Contact, Envelope, mime, store::Store};
+
+const BM25_K1: f64 = 1.2;
+const BM25_B: f64 = 0.75;
+
+/// Inverted index for a single cached folder.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct FolderIndex {
+    /// Number of indexed terms per message id.
+    docs: BTreeMap<String, u32>,
+    /// Term to message id to term frequency.
+    postings: BTreeMap<String, BTreeMap<String, u32>>,
+}
+
+/// A message matching a search, with its relevance score.
+pub struct Hit {
+    pub account: String,
+    pub folder: String,
+    pub id: String,
+    pub score: f64,
+}
+
+/// Bring the folder index in line with the synced envelopes: index messages
+/// that are new and drop messages that disappeared from the folder.
+pub fn update_folder_index(
+    store: &dyn Store,
+    account: &str,
+    folder: &str,
+    envelopes: &[Envelope],
+) -> Result<()> {
+    let mut index = load_index(store, account, folder)?;
+
+    let current: HashSet<&str> = envelopes
+        .iter()
+        .map(|envelope| envelope.id.as_str())
+        .collect();
+    let stale: Vec<String> = index
+        .docs
+        .keys()
+        .filter(|id| !current.contains(id.as_str()))
+        .cloned()
+        .collect();
+    for id in &stale {
+        index.remove(id);
+    }
+
+    let added: Vec<(String, HashMap<String, u32>)> = envelopes
+        .par_iter()
+        .filter(|envelope| !index.docs.contains_key(&envelope.id))
+        .filter_map(|envelope| {
+            let bytes = store.read_message(account, folder, &envelope.id).ok()?;
+            Some((envelope.id.clone(), term_frequencies(envelope, &bytes)))
+        })
+        .collect();
+    if stale.is_empty() && added.is_empty() {
+        return Ok(());
+    }
+    for (id, terms) in added {
+        index.insert(id, terms);
+    }
+
+    let data = serde_json::to_vec(&index).context("serialize search index")?;
+    store.put_search_index(account, folder, &data)
+}
+
+/// Search the indices of the given account and folder (all when `None`).
+/// Every term must match; hits are ranked with BM25 per folder.
+pub fn search(
+    store: &dyn Store,
+    account: Option<&str>,
+    folder: Option<&str>,
+    terms: &[String],
+) -> Result<Vec<Hit>> {
+    let terms: Vec<String> = terms.iter().flat_map(|term| tokenize(term)).collect();
+    if terms.is_empty() {
+        anyhow::bail!("search requires at least one term");
+    }
+
+    let mut hits = Vec::new();
+    let accounts = match account {
+        Some(account) => vec![account.to_string()],
+        None => store.account_names()?,
+    };
+    let mut folders = Vec::new();
+    for account in accounts {
+        match folder {
+            Some(folder) => folders.push((account, folder.to_string())),
+            None => {
+                for folder in store.folder_names(&account)? {
+                    folders.push((account.clone(), folder));
+                }
+            }
+        }
+    }
+
+    for (account, folder) in folders {
+        let index = store.folder_index(&account, &folder)?;
+        for (id, score) in index.score(&terms) {
+            hits.push(Hit {
+                account: account.clone(),
+                folder: folder.clone(),
+                id,
+                score,
+            });
+        }
+    }
+    hits.sort_by(|left, right| right.score.total_cmp(&left.score));
+    Ok(hits)
+}
+
+impl FolderIndex {
+    /// Index serialized in `data`; empty without one.
+    pub fn parse(data: Option<&[u8]>, account: &str, folder: &str) -> Result<Self> {
+        match data {
+            Some(data) => serde_json::from_slice(data)
+                .with_context(|| format!("parse search index of {account}/{folder}")),
+            None => Ok(Self::default()),
+        }
+    }
+
+    fn insert(&mut self, id: String, terms: HashMap<String, u32>) {
+        self.docs.insert(id.clone(), terms.values().sum());
+        for (term, count) in terms {
+            self.postings
+                .entry(term)
+                .or_default()
+                .insert(id.clone(), count);
+        }
+    }
+
+    fn remove(&mut self, id: &str) {
+        self.docs.remove(id);
+        self.postings.retain(|_, documents| {
+            documents.remove(id);
+            !documents.is_empty()
+        });
+    }
+
+    fn score(&self, terms: &[String]) -> Vec<(String, f64)> {
+        let total_docs = self.docs.len() as f64;
+        if total_docs == 0.0 {
+            return Vec::new();
+        }
+        let average_len = self.docs.values().map(|len| f64::from(*len)).sum::<f64>() / total_docs;
+
+        let mut scores: Option<HashMap<&str, f64>> = None;
+        for term in terms {
+            let Some(documents) = self.postings.get(term) else {
+                return Vec::new();
+            };
+            let doc_freq = documents.len() as f64;
+            let idf = ((total_docs - doc_freq + 0.5) / (doc_freq + 0.5) + 1.0).ln();
+            let term_scores: HashMap<&str, f64> = documents
+                .iter()
+                .map(|(id, count)| {
+                    let tf = f64::from(*count);
+                    let len = f64::from(self.docs.get(id).copied().unwrap_or_default());
+                    let norm = BM25_K1 * (1.0 - BM25_B + BM25_B * len / average_len.max(1.0));
+                    (id.as_str(), idf * tf * (BM25_K1 + 1.0) / (tf + norm))
+                })
+                .collect();
+            scores = Some(match scores {
+                None => term_scores,
+                Some(previous) => previous
+                    .into_iter()
+                    .filter_map(|(id, score)| term_scores.get(id).map(|extra| (id, score + extra)))
+                    .collect(),
+            });
+        }
+        scores
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(id, score)| (id.to_string(), score))
+            .collect()
+    }
+}
+
+fn load_index(store: &dyn Store, account: &str, folder: &str) -> Result<FolderIndex> {
+    let data = store.search_index(account, folder)?;
+    FolderIndex::parse(data.as_deref(), account, folder)
+}
+
+fn term_frequencies(envelope: &Envelope, bytes: &[u8]) -> HashMap<String, u32> {
+    let mut text = String::new();
+    text.push_str(envelope.subject.as_deref().unwrap_or_default());
+    for contact in envelope.contacts() {
+        push_contact(&mut text, contact);
+    }
+    if let Some(message) = mime::parse(bytes) {
+        text.push('\n');
+        text.push_str(&mime::searchable_text(&message));
+    }
+
+    let mut terms = HashMap::new();
+    for term in tokenize(&text) {
+        *terms.entry(term).or_insert(0) += 1;
+    }
+    terms
+}
+
+fn push_contact(text: &mut String, contact: &Contact) {
+    for value in [contact.name.as_deref(), contact.addr.as_deref()]
+        .into_iter()
+        .flatten()
+    {
+        text.push(' ');
+        text.push_str(value);
+    }
+}
+
+/// Split text into lowercase alphanumeric terms.
+fn tokenize(text: &str) -> Vec<String> {
+    text.split(|ch: char| !ch.is_alphanumeric())
+        .filter(|term| !term.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
@@ -0,0 +1,488 @@
+//! `serve --http`: a REST API over the cache for tools that cannot run
+//! commands. Everything is answered from the cache, as JSON except message
+//! bodies and attachments.
+//!
+//! - `GET /accounts`
+//! - `GET /accounts/<account>/folders`
+//! - `GET /accounts/<account>/folders/<folder>/envelopes?query=&page=&page_size=&extra_headers=`
+//! - `GET /accounts/<account>/folders/<folder>/messages/<id>?raw=&headers=&no_headers=&html=`
+//! - `GET /accounts/<account>/folders/<folder>/messages/<id>/attachments[/<index>]`
+//! - `GET /search?terms=&account=&folder=&sort=&extra_headers=`
+//! - `POST /sync?account=&folder=`, only with `--allow-sync`
+//!
+//! With a token, every request needs `Authorization: Bearer <token>`.
+//! Without one the server only listens on loopback addresses and only
+//! answers requests naming it by a loopback `Host`, so web pages cannot
+//! reach it through DNS rebinding.
+
+use anyhow::{Context, Result};
+use percent_encoding::percent_decode_str;
+use serde::Serialize;
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    net::{SocketAddr, ToSocketAddrs},
+    path::PathBuf,
+    sync::{Arc, TryLockError, atomic::Ordering},
+    thread,
+    time::Duration,
+};
+use tiny_http::{Header, Method, Request, Response};
+
+use super::Server;
+use crate::{
+    AttachmentEntry, EnvelopeListArgs, MessageReadArgs, SearchArgs, SyncArgs, attachment,
+    cached_envelopes, mime, query, render_message, resolve_folder, search_envelopes,
+};
+
+/// Environment variable holding the bearer token when `--token` is absent.
+pub const TOKEN_ENV: &str = "HIMALAYA_CACHE_TOKEN";
+
+/// Access rules of the HTTP server.
+pub struct Access {
+    /// Bearer token every request must carry.
+    pub token: Option<String>,
+    /// Whether `POST /sync` may run syncs; the server is read-only otherwise.
+    pub allow_sync: bool,
+}
+
+/// Error answered to a request, as `{"error": message}`.
+struct HttpError {
+    status: u16,
+    message: String,
+}
+
+impl From<anyhow::Error> for HttpError {
+    fn from(err: anyhow::Error) -> Self {
+        http_error(500, format!("{err:#}"))
+    }
+}
+
+/// Successful answer to a request.
+struct Reply {
+    status: u16,
+    content_type: &'static str,
+    body: Vec<u8>,
+    /// `Content-Disposition` of attachments.
+    disposition: Option<String>,
+}
+
+/// Serve the REST API on `address` until killed. With `sync_interval`, a
+/// full sync also runs in the background at that interval.
+pub fn serve_http(
+    cache_dir: PathBuf,
+    address: &str,
+    sync_interval: Option<Duration>,
+    access: Access,
+) -> Result<()> {
+    let addresses: Vec<SocketAddr> = address
+        .to_socket_addrs()
+        .with_context(|| format!("resolve {address}"))?
+        .collect();
+    if access.token.is_none() && !addresses.iter().all(|address| address.ip().is_loopback()) {
+        anyhow::bail!(
+            "{address} is not a loopback address; pass --token (or set ${TOKEN_ENV}) to serve it"
+        );
+    }
+    let listener = tiny_http::Server::http(address)
+        .map_err(|err| anyhow::anyhow!("listen on {address}: {err}"))?;
+    if access.token.is_none() {
+        eprintln!("warning: serving without a token, any local process can read the cache");
+    }
+    eprintln!("listening on http://{address}");
+
+    let port = listener
+        .server_addr()
+        .to_ip()
+        .map_or(0, |address| address.port());
+    let hosts = Arc::new(loopback_hosts(address, port));
+    let server = Server::start(cache_dir, sync_interval)?;
+    let access = Arc::new(access);
+    for request in listener.incoming_requests() {
+        let server = server.clone();
+        let access = access.clone();
+        let hosts = hosts.clone();
+        thread::spawn(move || {
+            let response = match answer(&server, &access, &hosts, &request) {
+                Ok(reply) => {
+                    let mut response = Response::from_data(reply.body)
+                        .with_status_code(reply.status)
+                        .with_header(header("Content-Type", reply.content_type));
+                    if let Some(disposition) = &reply.disposition {
+                        response.add_header(header("Content-Disposition", disposition));
+                    }
+                    response
+                }
+                Err(err) => {
+                    let mut response = Response::from_data(
+                        json!({ "error": err.message }).to_string().into_bytes(),
+                    )
+                    .with_status_code(err.status)
+                    .with_header(header("Content-Type", "application/json"));
+                    if err.status == 401 {
+                        response.add_header(header("WWW-Authenticate", "Bearer"));
+                    }
+                    response
+                }
+            };
+            if let Err(err) = request.respond(response) {
+                eprintln!("warning: answer request: {err}");
+            }
+        });
+    }
+    Ok(())
+}
+
+fn answer(
+    server: &Arc<Server>,
+    access: &Access,
+    hosts: &[String],
+    request: &Request,
+) -> Result<Reply, HttpError> {
+    let header_value = |name: &'static str| {
+        request
+            .headers()
+            .iter()
+            .find(|header| header.field.equiv(name))
+            .map(|header| header.value.as_str())
+    };
+    match &access.token {
+        Some(token) => {
+            let given =
+                header_value("Authorization").and_then(|value| value.strip_prefix("Bearer "));
+            if !given.is_some_and(|given| same_token(given.trim(), token)) {
+                return Err(http_error(401, "missing or wrong bearer token".to_string()));
+            }
+        }
+        None => {
+            let host = header_value("Host").map(str::to_ascii_lowercase);
+            if !host.is_some_and(|host| hosts.contains(&host)) {
+                return Err(http_error(403, "unexpected Host header".to_string()));
+            }
+        }
+    }
+
+    let (path, query) = request.url().split_once('?').unwrap_or((request.url(), ""));
+    let segments: Vec<String> = path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(|segment| percent_decode_str(segment).decode_utf8_lossy().into_owned())
+        .collect();
+    let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+    let mut params = Params::parse(query);
+
+    if let ["sync"] = segments[..] {
+        if *request.method() != Method::Post {
+            return Err(http_error(405, "use POST to start a sync".to_string()));
+        }
+        if !access.allow_sync {
+            return Err(http_error(
+                403,
+                "the server is read-only; start it with --allow-sync".to_string(),
+            ));
+        }
+        let args = SyncArgs {
+            account: params.take("account"),
+            folder: params.take("folder"),
+        };
+        params.finish()?;
+        return start_sync(server, args);
+    }
+    if *request.method() != Method::Get {
+        return Err(http_error(405, "only GET is supported here".to_string()));
+    }
+
+    let store = server.store()?;
+    let store = store.as_ref();
+    match segments[..] {
+        ["accounts"] => {
+            params.finish()?;
+            json_reply(&store.accounts()?.unwrap_or_default())
+        }
+        ["accounts", account, "folders"] => {
+            params.finish()?;
+            let folders = store.folders(account)?.ok_or_else(|| {
+                http_error(404, format!("folders of account {account} are not cached"))
+            })?;
+            json_reply(&folders)
+        }
+        ["accounts", account, "folders", folder, "envelopes"] => {
+            let args = EnvelopeListArgs {
+                account: Some(account.to_string()),
+                folder: Some(folder.to_string()),
+                thread: false,
+                output: "json".to_string(),
+                page: params.number("page")?.unwrap_or(1),
+                page_size: params.number("page_size")?,
+                extra_headers: params.flag("extra_headers")?,
+                query: query::split(&params.take("query").unwrap_or_default())
+                    .map_err(|err| http_error(400, format!("{err:#}")))?,
+            };
+            params.finish()?;
+            if args.page == 0 {
+                return Err(http_error(400, "pages start at 1".to_string()));
+            }
+            if args.page_size == Some(0) {
+                return Err(http_error(
+                    400,
+                    "pages hold at least one envelope".to_string(),
+                ));
+            }
+            json_reply(&cached_envelopes(store, &args)?)
+        }
+        [
+            "accounts",
+            account,
+            "folders",
+            folder,
+            "messages",
+            id,
+            ref rest @ ..,
+        ] => {
+            let folder = resolve_folder(account, Some(folder.to_string()))?;
+            if !store.has_message(account, &folder, id)? {
+                return Err(http_error(
+                    404,
+                    format!("message {id} of {account}/{folder} is not cached"),
+                ));
+            }
+            let contents = store.read_message(account, &folder, id)?;
+            match rest {
+                [] => {
+                    let raw = params.flag("raw")?;
+                    let args = MessageReadArgs {
+                        account: Some(account.to_string()),
+                        folder: Some(folder),
+                        headers: params
+                            .take("headers")
+                            .map(|headers| headers.split(',').map(str::to_string).collect())
+                            .unwrap_or_default(),
+                        raw,
+                        output_file: None,
+                        no_headers: params.flag("no_headers")?,
+                        html: params.flag("html")?,
+                        id: id.to_string(),
+                    };
+                    params.finish()?;
+                    if raw {
+                        return Ok(reply(200, "message/rfc822", contents));
+                    }
+                    let rendered = render_message(&contents, args)?;
+                    Ok(reply(
+                        200,
+                        "text/plain; charset=utf-8",
+                        rendered.into_bytes(),
+                    ))
+                }
+                ["attachments", which @ ..] => {
+                    params.finish()?;
+                    let message =
+                        mime::parse(&contents).with_context(|| format!("parse message {id}"))?;
+                    let attachments = mime::attachments(&message);
+                    let [index] = which else {
+                        let entries: Vec<AttachmentEntry> = attachments
+                            .into_iter()
+                            .map(|attachment| AttachmentEntry {
+                                filename: attachment.filename,
+                                mime: attachment.mime,
+                                size: attachment.contents.len(),
+                            })
+                            .collect();
+                        return json_reply(&entries);
+                    };
+                    let found = index
+                        .parse::<usize>()
+                        .ok()
+                        .and_then(|index| Some((index, attachments.get(index)?)));
+                    let Some((index, found)) = found else {
+                        return Err(http_error(
+                            404,
+                            format!("message {id} has no attachment {index}"),
+                        ));
+                    };
+                    let filename =
+                        attachment::safe_filename(found.filename.as_deref(), &found.mime, index);
+                    Ok(Reply {
+                        disposition: Some(format!(
+                            "attachment; filename=\"{}\"",
+                            header_safe(&filename)
+                        )),
+                        ..reply(200, content_type(&found.mime), found.contents.to_vec())
+                    })
+                }
+                _ => Err(not_found()),
+            }
+        }
+        ["search"] => {
+            let args = SearchArgs {
+                account: params.take("account"),
+                folder: params.take("folder"),
+                sort: params
+                    .take("sort")
+                    .unwrap_or_else(|| "relevance".to_string()),
+                extra_headers: params.flag("extra_headers")?,
+                terms: params.words("terms"),
+            };
+            params.finish()?;
+            if args.terms.is_empty() {
+                return Err(http_error(400, "terms are required".to_string()));
+            }
+            json_reply(&search_envelopes(store, args)?)
+        }
+        _ => Err(not_found()),
+    }
+}
+
+/// Run a sync in the background, unless one is already running.
+fn start_sync(server: &Arc<Server>, args: SyncArgs) -> Result<Reply, HttpError> {
+    // The flag, unlike the lock, can be held until the spawned sync ends, so
+    // two requests cannot both start one.
+    let busy = || http_error(409, "a sync is already running".to_string());
+    if server
+        .sync_started
+        .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+        .is_err()
+    {
+        return Err(busy());
+    }
+    if let Err(TryLockError::WouldBlock) = server.syncing.try_lock() {
+        server.sync_started.store(false, Ordering::Release);
+        return Err(busy());
+    }
+    let server = server.clone();
+    thread::spawn(move || {
+        if let Err(err) = server.sync(args) {
+            eprintln!("warning: sync failed: {err:#}");
+        }
+        server.sync_started.store(false, Ordering::Release);
+    });
+    Ok(reply(
+        202,
+        "application/json",
+        json!({ "status": "started" }).to_string().into_bytes(),
+    ))
+}
+
+/// Query string parameters, taken one by one so unknown ones are rejected.
+struct Params(HashMap<String, String>);
+
+impl Params {
+    fn parse(query: &str) -> Self {
+        Params(
+            form_urlencoded::parse(query.as_bytes())
+                .map(|(name, value)| (name.into_owned(), value.into_owned()))
+                .collect(),
+        )
+    }
+
+    fn take(&mut self, name: &str) -> Option<String> {
+        self.0.remove(name)
+    }
+
+    /// Whitespace-separated words, like the trailing words of a command.
+    fn words(&mut self, name: &str) -> Vec<String> {
+        self.take(name)
+            .map(|value| value.split_whitespace().map(str::to_string).collect())
+            .unwrap_or_default()
+    }
+
+    /// A switch, set by `name`, `name=true` or `name=1`.
+    fn flag(&mut self, name: &str) -> Result<bool, HttpError> {
+        match self.take(name).as_deref() {
+            None | Some("false" | "0") => Ok(false),
+            Some("" | "true" | "1") => Ok(true),
+            Some(other) => Err(http_error(400, format!("invalid {name} {other:?}"))),
+        }
+    }
+
+    fn number(&mut self, name: &str) -> Result<Option<usize>, HttpError> {
+        self.take(name)
+            .map(|value| {
+                value
+                    .parse()
+                    .map_err(|_| http_error(400, format!("invalid {name} {value:?}")))
+            })
+            .transpose()
+    }
+
+    fn finish(self) -> Result<(), HttpError> {
+        match self.0.keys().min() {
+            Some(name) => Err(http_error(400, format!("unknown parameter {name:?}"))),
+            None => Ok(()),
+        }
+    }
+}
+
+fn json_reply<T: Serialize>(value: &T) -> Result<Reply, HttpError> {
+    let body = serde_json::to_vec_pretty(value).context("serialize result")?;
+    Ok(reply(200, "application/json", body))
+}
+
+fn reply(status: u16, content_type: &'static str, body: Vec<u8>) -> Reply {
+    Reply {
+        status,
+        content_type,
+        body,
+        disposition: None,
+    }
+}
+
+/// Content type of an attachment, kept generic so browsers never render it
+/// in the page of the server.
+fn content_type(mime: &str) -> &'static str {
+    match mime {
+        "application/pdf" => "application/pdf",
+        "image/png" => "image/png",
+        "image/jpeg" => "image/jpeg",
+        "image/gif" => "image/gif",
+        "text/plain" => "text/plain; charset=utf-8",
+        _ => "application/octet-stream",
+    }
+}
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).expect("valid header")
+}
+
+/// Keep a filename to the printable ASCII a header value allows.
+fn header_safe(filename: &str) -> String {
+    filename
+        .chars()
+        .map(|ch| match ch {
+            ' '..='~' if ch != '"' && ch != '\\' => ch,
+            _ => '_',
+        })
+        .collect()
+}
+
+fn http_error(status: u16, message: String) -> HttpError {
+    HttpError { status, message }
+}
+
+fn not_found() -> HttpError {
+    http_error(404, "no such endpoint".to_string())
+}
+
+/// `Host` headers naming the server when it listens on loopback `address`
+/// and `port`: the address as given, and the loopback names.
+fn loopback_hosts(address: &str, port: u16) -> Vec<String> {
+    let mut hosts: Vec<String> = ["localhost", "127.0.0.1", "[::1]"]
+        .iter()
+        .map(|name| format!("{name}:{port}"))
+        .collect();
+    hosts.push(address.to_ascii_lowercase());
+    if port == 80 {
+        hosts.extend(["localhost", "127.0.0.1", "[::1]"].map(String::from));
+    }
+    hosts
+}
+
+/// Compare tokens in constant time.
+fn same_token(given: &str, token: &str) -> bool {
+    given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
@@ -0,0 +1,529 @@
+//! `serve`: a long-lived JSON-RPC 2.0 server for editor integrations,
+//! reading one request per line and writing one response or notification
+//! per line, either on stdin and stdout (`--stdio`) or on each connection to
+//! a Unix socket (`--socket`). `--http` serves a REST API instead, see
+//! [`http`].
+//!
+//! Methods: `account.list`, `folder.list`, `envelope.list`, `message.read`,
+//! `search` and `sync`, taking the options of the matching commands as named
+//! params, and `command`, which returns the output of a forwarded read command.
+//! `message.read` with `raw` answers `{"encoding": "base64", "data": ...}`, as
+//! the original bytes need not be UTF-8. Syncs run in the background; when one
+//! finishes the server sends a `sync.finished` notification, plus a `mail.new`
+//! notification for each folder that received messages, to every client.
+//!
+//! Commands run by other processes mark the cache changed, and the server
+//! reopens it before its next request.
+
+use anyhow::{Context, Result};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::{
+    io::{self, BufRead, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex, PoisonError, atomic::AtomicBool, mpsc},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+mod http;
+
+pub use http::{Access, TOKEN_ENV, serve_http};
+
+use crate::{
+    CachedCommand, EnvelopeListArgs, FolderSynced, MessageReadArgs, SearchArgs, SyncArgs,
+    cached_envelopes, cached_output, open_store, query, render_message, resolve_account,
+    resolve_folder, search_envelopes,
+    store::{self, CachedStore, Store},
+    sync_store,
+};
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const SERVER_ERROR: i64 = -32000;
+
+#[derive(Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+/// Error answered to a request.
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl From<anyhow::Error> for RpcError {
+    fn from(err: anyhow::Error) -> Self {
+        RpcError {
+            code: SERVER_ERROR,
+            message: format!("{err:#}"),
+        }
+    }
+}
+
+/// A list of words given either as one string or as an array.
+#[derive(Default, Deserialize)]
+#[serde(untagged)]
+enum Words {
+    #[default]
+    None,
+    Line(String),
+    List(Vec<String>),
+}
+
+impl Words {
+    fn into_vec(self) -> Vec<String> {
+        match self {
+            Words::None => Vec::new(),
+            Words::Line(line) => line.split_whitespace().map(str::to_string).collect(),
+            Words::List(words) => words,
+        }
+    }
+
+    /// Query arguments; a line is split the way the query parser reads it,
+    /// so quoted values keep their spaces.
+    fn into_query(self) -> Result<Vec<String>> {
+        match self {
+            Words::Line(line) => query::split(&line),
+            words => Ok(words.into_vec()),
+        }
+    }
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct AccountParams {
+    account: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct EnvelopeListParams {
+    account: Option<String>,
+    folder: Option<String>,
+    query: Words,
+    page: Option<usize>,
+    page_size: Option<usize>,
+    extra_headers: bool,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct MessageReadParams {
+    account: Option<String>,
+    folder: Option<String>,
+    id: String,
+    headers: Vec<String>,
+    raw: bool,
+    no_headers: bool,
+    html: bool,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct SearchParams {
+    account: Option<String>,
+    folder: Option<String>,
+    terms: Words,
+    sort: Option<String>,
+    extra_headers: bool,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct SyncParams {
+    account: Option<String>,
+    folder: Option<String>,
+}
+
+/// Shared state of the server threads.
+struct Server {
+    cache_dir: PathBuf,
+    /// Store opened after the last change of the cache seen.
+    store: Mutex<Opened>,
+    /// Output of each connected client, for notifications.
+    clients: Mutex<Vec<mpsc::Sender<Value>>>,
+    /// Held while a sync runs, so syncs never overlap.
+    syncing: Mutex<()>,
+    /// Set from the moment `POST /sync` starts a sync until it ends.
+    sync_started: AtomicBool,
+}
+
+struct Opened {
+    stamp: Option<String>,
+    store: Arc<dyn Store>,
+}
+
+/// Serve requests from stdin until it closes. With `sync_interval`, a full
+/// sync also runs in the background at that interval.
+pub fn serve_stdio(cache_dir: PathBuf, sync_interval: Option<Duration>) -> Result<()> {
+    let server = Server::start(cache_dir, sync_interval)?;
+    let (output, writer) = spawn_writer(io::stdout());
+    server.connect(output.clone());
+    server.serve(io::stdin().lock(), &output)?;
+
+    // Let a background sync finish and announce itself before stopping.
+    drop(
+        server
+            .syncing
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner),
+    );
+    let _ = output.send(Value::Null);
+    writer
+        .join()
+        .map_err(|_| anyhow::anyhow!("response writer panicked"))?
+}
+
+/// Serve each connection to the socket of the cache, until killed.
+#[cfg(unix)]
+pub fn serve_socket(cache_dir: PathBuf, sync_interval: Option<Duration>) -> Result<()> {
+    use std::os::unix::{
+        fs::DirBuilderExt,
+        net::{UnixListener, UnixStream},
+    };
+
+    let path = crate::daemon::socket_path(&cache_dir)
+        .context("XDG_RUNTIME_DIR is not set, so there is no place for the socket")?;
+    if let Some(dir) = path.parent() {
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)
+            .with_context(|| format!("create {}", dir.display()))?;
+    }
+    if path.exists() {
+        if UnixStream::connect(&path).is_ok() {
+            anyhow::bail!("a server already listens on {}", path.display());
+        }
+        // Left behind by a server that did not stop cleanly.
+        std::fs::remove_file(&path).with_context(|| format!("remove {}", path.display()))?;
+    }
+    let listener =
+        UnixListener::bind(&path).with_context(|| format!("listen on {}", path.display()))?;
+    eprintln!("listening on {}", path.display());
+
+    let server = Server::start(cache_dir, sync_interval)?;
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                eprintln!("warning: accept connection: {err}");
+                continue;
+            }
+        };
+        let server = server.clone();
+        thread::spawn(move || {
+            if let Err(err) = server.serve_stream(stream) {
+                eprintln!("warning: connection failed: {err:#}");
+            }
+        });
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn serve_socket(_cache_dir: PathBuf, _sync_interval: Option<Duration>) -> Result<()> {
+    anyhow::bail!("--socket is only supported on Unix")
+}
+
+/// Write the messages sent to the returned channel on their own line, until
+/// Null is sent.
+fn spawn_writer<W: Write + Send + 'static>(
+    mut writer: W,
+) -> (mpsc::Sender<Value>, JoinHandle<Result<()>>) {
+    let (output, messages) = mpsc::channel::<Value>();
+    let writer = thread::spawn(move || -> Result<()> {
+        for message in messages.iter().take_while(|message| !message.is_null()) {
+            serde_json::to_writer(&mut writer, &message).context("write response")?;
+            writer.write_all(b"\n").context("write response")?;
+            writer.flush().context("write response")?;
+        }
+        Ok(())
+    });
+    (output, writer)
+}
+
+impl Server {
+    /// Open the cache, and start the background sync when asked to.
+    fn start(cache_dir: PathBuf, sync_interval: Option<Duration>) -> Result<Arc<Self>> {
+        let server = Arc::new(Server {
+            store: Mutex::new(Opened {
+                stamp: store::changed_stamp(&cache_dir),
+                store: Arc::new(CachedStore::new(open_store()?)),
+            }),
+            cache_dir,
+            clients: Mutex::new(Vec::new()),
+            syncing: Mutex::new(()),
+            sync_started: AtomicBool::new(false),
+        });
+        if let Some(interval) = sync_interval {
+            let server = server.clone();
+            thread::spawn(move || {
+                loop {
+                    thread::sleep(interval);
+                    if let Err(err) = server.sync(SyncArgs {
+                        account: None,
+                        folder: None,
+                    }) {
+                        eprintln!("warning: background sync failed: {err:#}");
+                    }
+                }
+            });
+        }
+        Ok(server)
+    }
+
+    /// The store, reopened when another process changed the cache since it
+    /// was opened.
+    fn store(&self) -> Result<Arc<dyn Store>> {
+        let mut opened = self.store.lock().unwrap_or_else(PoisonError::into_inner);
+        let stamp = store::changed_stamp(&self.cache_dir);
+        if stamp != opened.stamp {
+            opened.store = Arc::new(CachedStore::new(open_store()?));
+            opened.stamp = stamp;
+        }
+        Ok(opened.store.clone())
+    }
+
+    fn connect(&self, output: mpsc::Sender<Value>) {
+        self.clients
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(output);
+    }
+
+    #[cfg(unix)]
+    fn serve_stream(self: &Arc<Self>, stream: std::os::unix::net::UnixStream) -> Result<()> {
+        let (output, writer) = spawn_writer(stream.try_clone().context("clone connection")?);
+        self.connect(output.clone());
+        let served = self.serve(io::BufReader::new(stream), &output);
+        let _ = output.send(Value::Null);
+        let written = writer
+            .join()
+            .map_err(|_| anyhow::anyhow!("response writer panicked"))?;
+        served.and(written)
+    }
+
+    /// Answer the requests read from `input` until it closes, then wait for
+    /// the syncs they started.
+    fn serve(self: &Arc<Self>, input: impl BufRead, output: &mpsc::Sender<Value>) -> Result<()> {
+        let mut syncs = Vec::new();
+        for line in input.lines() {
+            let line = line.context("read request")?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            syncs.extend(self.handle(&line, output));
+            syncs.retain(|sync| !sync.is_finished());
+        }
+        for sync in syncs {
+            let _ = sync.join();
+        }
+        Ok(())
+    }
+
+    /// Send a notification to every client still connected.
+    fn notify(&self, method: &str, params: Value) {
+        let message = json!({ "jsonrpc": "2.0", "method": method, "params": params });
+        self.clients
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|client| client.send(message.clone()).is_ok());
+    }
+
+    /// Answer one request line. Syncs answer from their own thread, which
+    /// is returned.
+    fn handle(
+        self: &Arc<Self>,
+        line: &str,
+        output: &mpsc::Sender<Value>,
+    ) -> Option<JoinHandle<()>> {
+        let request: Value = match serde_json::from_str(line) {
+            Ok(request) => request,
+            Err(err) => {
+                let message = format!("parse request: {err}");
+                respond(output, Value::Null, Err(rpc_error(PARSE_ERROR, message)));
+                return None;
+            }
+        };
+        let request: Request = match serde_json::from_value(request.clone()) {
+            Ok(request) => request,
+            Err(err) => {
+                let id = request.get("id").cloned().unwrap_or(Value::Null);
+                let message = format!("invalid request: {err}");
+                respond(output, id, Err(rpc_error(INVALID_REQUEST, message)));
+                return None;
+            }
+        };
+
+        if request.method == "sync" {
+            // Syncs take a while, so other requests keep being served
+            // meanwhile.
+            let server = self.clone();
+            let output = output.clone();
+            return Some(thread::spawn(move || {
+                let result = parse_params::<SyncParams>(request.params).and_then(|params| {
+                    let synced = server.sync(SyncArgs {
+                        account: params.account,
+                        folder: params.folder,
+                    })?;
+                    Ok(serde_json::to_value(synced).context("serialize sync result")?)
+                });
+                if let Some(id) = request.id {
+                    respond(&output, id, result);
+                }
+            }));
+        }
+
+        let result = self.call(&request.method, request.params);
+        if let Some(id) = request.id {
+            respond(output, id, result);
+        }
+        None
+    }
+
+    fn call(&self, method: &str, params: Value) -> Result<Value, RpcError> {
+        let store = self.store()?;
+        let store = store.as_ref();
+        let result = match method {
+            "account.list" => serde_json::to_value(store.accounts()?.unwrap_or_default()),
+            "folder.list" => {
+                let params = parse_params::<AccountParams>(params)?;
+                let account = resolve_account(params.account)?;
+                serde_json::to_value(store.folders(&account)?.unwrap_or_default())
+            }
+            "envelope.list" => {
+                let params = parse_params::<EnvelopeListParams>(params)?;
+                let envelopes = cached_envelopes(
+                    store,
+                    &EnvelopeListArgs {
+                        account: Some(resolve_account(params.account)?),
+                        folder: params.folder,
+                        thread: false,
+                        output: "json".to_string(),
+                        page: params.page.unwrap_or(1),
+                        page_size: params.page_size,
+                        extra_headers: params.extra_headers,
+                        query: params.query.into_query()?,
+                    },
+                )?;
+                serde_json::to_value(envelopes)
+            }
+            "message.read" => {
+                let params = parse_params::<MessageReadParams>(params)?;
+                if params.id.is_empty() {
+                    return Err(rpc_error(INVALID_PARAMS, "id is required".to_string()));
+                }
+                let account = resolve_account(params.account)?;
+                let folder = resolve_folder(&account, params.folder)?;
+                let contents = store.read_message(&account, &folder, &params.id)?;
+                if params.raw {
+                    // Bodies need not be UTF-8; base64 keeps every byte.
+                    return Ok(json!({
+                        "encoding": "base64",
+                        "data": BASE64_STANDARD.encode(&contents),
+                    }));
+                }
+                let message = render_message(
+                    &contents,
+                    MessageReadArgs {
+                        account: Some(account),
+                        folder: Some(folder),
+                        headers: params.headers,
+                        raw: false,
+                        output_file: None,
+                        no_headers: params.no_headers,
+                        html: params.html,
+                        id: params.id,
+                    },
+                )?;
+                Ok(Value::String(message))
+            }
+            "search" => {
+                let params = parse_params::<SearchParams>(params)?;
+                let terms = params.terms.into_vec();
+                if terms.is_empty() {
+                    return Err(rpc_error(INVALID_PARAMS, "terms are required".to_string()));
+                }
+                let hits = search_envelopes(
+                    store,
+                    SearchArgs {
+                        account: params.account,
+                        folder: params.folder,
+                        sort: params.sort.unwrap_or_else(|| "relevance".to_string()),
+                        extra_headers: params.extra_headers,
+                        terms,
+                    },
+                )?;
+                serde_json::to_value(hits)
+            }
+            "command" => {
+                let command = serde_json::from_value::<CachedCommand>(params)
+                    .map_err(|err| rpc_error(INVALID_PARAMS, format!("invalid params: {err}")))?;
+                Ok(Value::String(cached_output(store, command)?))
+            }
+            other => {
+                return Err(rpc_error(
+                    METHOD_NOT_FOUND,
+                    format!("unknown method {other:?}"),
+                ));
+            }
+        };
+        Ok(result.context("serialize result")?)
+    }
+
+    /// Run a sync, then announce what it brought.
+    fn sync(&self, args: SyncArgs) -> Result<Vec<FolderSynced>> {
+        let _guard = self.syncing.lock().unwrap_or_else(PoisonError::into_inner);
+        let synced = sync_store(self.store()?.as_ref(), args)?;
+        // The sync went through the store in use, so it stays current.
+        store::mark_changed(&self.cache_dir)?;
+        self.store
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .stamp = store::changed_stamp(&self.cache_dir);
+        for folder in synced.iter().filter(|folder| !folder.new.is_empty()) {
+            self.notify(
+                "mail.new",
+                json!({ "account": folder.account, "folder": folder.folder, "ids": folder.new }),
+            );
+        }
+        self.notify("sync.finished", json!({ "folders": synced }));
+        Ok(synced)
+    }
+}
+
+fn respond(output: &mpsc::Sender<Value>, id: Value, result: Result<Value, RpcError>) {
+    let message = match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(err) => json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": { "code": err.code, "message": err.message },
+        }),
+    };
+    // Fails only once the writer stopped, when nobody is listening.
+    let _ = output.send(message);
+}
+
+fn rpc_error(code: i64, message: String) -> RpcError {
+    RpcError { code, message }
+}
+
+/// Named params of a method; absent params mean every option is unset.
+fn parse_params<T: for<'de> Deserialize<'de> + Default>(params: Value) -> Result<T, RpcError> {
+    if params.is_null() {
+        return Ok(T::default());
+    }
+    serde_json::from_value(params)
+        .map_err(|err| rpc_error(INVALID_PARAMS, format!("invalid params: {err}")))
+}
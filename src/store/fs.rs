@@ -0,0 +1,754 @@
+//! Directory layout backend: `accounts.json`, `folders/<account>.json`,
+//! `envelopes/<account>/<folder>.json`, `meta/<account>/<folder>/<id>.json`,
+//! `messages/<account>/<folder>/<id>.eml` and `search/<account>/<folder>.json`.
+//!
+//! Bodies live once in `blobs/<xx>/<sha256>.eml` and each
+//! `messages/<account>/<folder>/<id>.eml` is a hard link to its blob, so
+//! readers of the historical layout keep working. A blob whose only link is
+//! itself is unreferenced.
+//!
+//! Account, folder and id path components are encoded with
+//! [`encode_component`], so `[Gmail]/Sent Mail` is the single component
+//! `%5BGmail%5D%2FSent Mail` and no name can nest or escape the cache.
+//!
+//! `envelopes/<account>/<folder>.json` is the folder index: every envelope of
+//! the folder in one compact file, newest first. Listing reads only the
+//! index; the per-envelope meta files are derived from it and rebuilt when
+//! missing.
+
+use anyhow::{Context, Result};
+use rayon::prelude::*;
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use super::{Compression, Store, content_hash, decode, sort_by_date};
+use crate::{Account, Envelope, Folder, write_bytes, write_json};
+
+pub struct FsStore {
+    root: PathBuf,
+    compression: Compression,
+}
+
+impl FsStore {
+    pub fn new(root: &Path, compression: Compression) -> Self {
+        Self {
+            root: root.to_path_buf(),
+            compression,
+        }
+    }
+
+    /// Delete every file of the directory layout under the cache root.
+    pub fn remove(&self) -> Result<()> {
+        let accounts = self.root.join("accounts.json");
+        if accounts.exists() {
+            fs::remove_file(&accounts).with_context(|| format!("remove {}", accounts.display()))?;
+        }
+        for name in [
+            "folders",
+            "envelopes",
+            "meta",
+            "messages",
+            "blobs",
+            "search",
+        ] {
+            let dir = self.root.join(name);
+            if dir.exists() {
+                fs::remove_dir_all(&dir).with_context(|| format!("remove {}", dir.display()))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Move every file written under raw names to its encoded path. Nested
+    /// folders such as `Work/2024` are found by walking the index
+    /// directories, as they were listed before encoding. Components that are
+    /// already encoded are taken as such, so running this twice is harmless,
+    /// unless the account list, folder list or folder index records the
+    /// component itself as a name.
+    pub fn encode_paths(&self) -> Result<()> {
+        /// Name behind an on-disk component, raw or already encoded.
+        fn name_of(component: &str, known: &HashSet<String>) -> String {
+            if !known.contains(component) && is_encoded(component) {
+                decode_component(component).unwrap_or_else(|| component.to_string())
+            } else {
+                component.to_string()
+            }
+        }
+
+        /// Collect `(path on disk, whether nested)` of every index file.
+        fn walk(dir: &Path, prefix: &str, folders: &mut Vec<(String, bool)>) -> Result<()> {
+            for entry in read_dir_entries(dir)? {
+                let component = entry.file_name().to_string_lossy().into_owned();
+                let joined = if prefix.is_empty() {
+                    component.clone()
+                } else {
+                    format!("{prefix}/{component}")
+                };
+                if entry.path().is_dir() {
+                    walk(&entry.path(), &joined, folders)?;
+                } else if let Some(stem) = joined.strip_suffix(".json") {
+                    folders.push((stem.to_string(), !prefix.is_empty()));
+                }
+            }
+            Ok(())
+        }
+
+        let accounts: HashSet<String> = self
+            .accounts()?
+            .unwrap_or_default()
+            .into_iter()
+            .map(|account| account.name)
+            .collect();
+        for account in read_dir_entries(&self.root.join("envelopes"))? {
+            let disk_account = account.file_name().to_string_lossy().into_owned();
+            let account_name = name_of(&disk_account, &accounts);
+            let folder_list = self
+                .root
+                .join("folders")
+                .join(format!("{disk_account}.json"));
+            let folder_list = match read_json::<Vec<Folder>>(&folder_list)? {
+                Some(folders) => Some(folders),
+                None => self.folders(&account_name)?,
+            };
+            let known_folders: HashSet<String> = folder_list
+                .unwrap_or_default()
+                .into_iter()
+                .map(|folder| folder.name)
+                .collect();
+            let mut folders = Vec::new();
+            walk(&account.path(), "", &mut folders)?;
+            for (disk_folder, nested) in &folders {
+                let folder = if *nested {
+                    disk_folder.clone()
+                } else {
+                    name_of(disk_folder, &known_folders)
+                };
+                let folder = folder.as_str();
+                let disk = |kind: &str| self.root.join(kind).join(&disk_account).join(disk_folder);
+                let disk_file = |kind: &str| {
+                    self.root
+                        .join(kind)
+                        .join(&disk_account)
+                        .join(format!("{disk_folder}.json"))
+                };
+                move_file(
+                    &disk_file("envelopes"),
+                    &self.index_path(&account_name, folder),
+                )?;
+                let ids: HashSet<String> =
+                    read_json::<Vec<Envelope>>(&self.index_path(&account_name, folder))?
+                        .unwrap_or_default()
+                        .into_iter()
+                        .map(|envelope| envelope.id)
+                        .collect();
+                move_file(
+                    &disk_file("search"),
+                    &self.search_path(&account_name, folder),
+                )?;
+                for entry in read_dir_entries(&disk("meta"))? {
+                    let component = entry.file_name().to_string_lossy().into_owned();
+                    if let Some(id) = component.strip_suffix(".json") {
+                        let target = self.meta_path(&account_name, folder, &name_of(id, &ids));
+                        move_file(&entry.path(), &target)?;
+                    }
+                }
+                for entry in read_dir_entries(&disk("messages"))? {
+                    let component = entry.file_name().to_string_lossy().into_owned();
+                    let target = if let Some(id) = component.strip_suffix(".eml.zst") {
+                        self.message_path(&account_name, folder, &name_of(id, &ids), true)
+                    } else if let Some(id) = component.strip_suffix(".eml") {
+                        self.message_path(&account_name, folder, &name_of(id, &ids), false)
+                    } else {
+                        continue;
+                    };
+                    move_file(&entry.path(), &target)?;
+                }
+            }
+            move_file(
+                &self
+                    .root
+                    .join("folders")
+                    .join(format!("{disk_account}.json")),
+                &self.folders_path(&account_name),
+            )?;
+        }
+        for kind in ["envelopes", "meta", "messages", "search"] {
+            remove_empty_dirs(&self.root.join(kind))?;
+        }
+        Ok(())
+    }
+
+    fn folders_path(&self, account: &str) -> PathBuf {
+        self.root
+            .join("folders")
+            .join(format!("{}.json", encode_component(account)))
+    }
+
+    fn index_path(&self, account: &str, folder: &str) -> PathBuf {
+        self.root
+            .join("envelopes")
+            .join(encode_component(account))
+            .join(format!("{}.json", encode_component(folder)))
+    }
+
+    fn write_index(&self, account: &str, folder: &str, envelopes: &[Envelope]) -> Result<()> {
+        let data = serde_json::to_vec(envelopes).context("serialize envelope index")?;
+        write_bytes(&self.index_path(account, folder), &data)
+    }
+
+    /// Envelopes of a folder read from its meta files, for caches written
+    /// before the folder had an index.
+    fn read_meta_dir(&self, account: &str, folder: &str) -> Result<Vec<Envelope>> {
+        let meta_dir = self.meta_dir(account, folder);
+        if !meta_dir.exists() {
+            return Ok(Vec::new());
+        }
+        let mut envelopes = Vec::new();
+        for entry in
+            fs::read_dir(&meta_dir).with_context(|| format!("read {}", meta_dir.display()))?
+        {
+            let entry = entry.with_context(|| format!("read entry in {}", meta_dir.display()))?;
+            let path = entry.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+                continue;
+            }
+            let data = fs::read(&path).with_context(|| format!("read {}", path.display()))?;
+            let envelope: Envelope = serde_json::from_slice(&data)
+                .with_context(|| format!("parse {}", path.display()))?;
+            envelopes.push(envelope);
+        }
+        Ok(envelopes)
+    }
+
+    fn meta_dir(&self, account: &str, folder: &str) -> PathBuf {
+        self.root
+            .join("meta")
+            .join(encode_component(account))
+            .join(encode_component(folder))
+    }
+
+    fn meta_path(&self, account: &str, folder: &str, id: &str) -> PathBuf {
+        self.meta_dir(account, folder)
+            .join(format!("{}.json", encode_component(id)))
+    }
+
+    /// Path of a message body: `<id>.eml`, or `<id>.eml.zst` when compressed.
+    fn message_path(&self, account: &str, folder: &str, id: &str, compressed: bool) -> PathBuf {
+        let extension = if compressed { "eml.zst" } else { "eml" };
+        self.root
+            .join("messages")
+            .join(encode_component(account))
+            .join(encode_component(folder))
+            .join(format!("{}.{extension}", encode_component(id)))
+    }
+
+    fn blob_path(&self, hash: &str, compressed: bool) -> PathBuf {
+        let extension = if compressed { "eml.zst" } else { "eml" };
+        self.root
+            .join("blobs")
+            .join(&hash[..2])
+            .join(format!("{hash}.{extension}"))
+    }
+
+    /// Existing body file of a message, compressed or not.
+    fn existing_message_path(&self, account: &str, folder: &str, id: &str) -> Option<PathBuf> {
+        [true, false]
+            .into_iter()
+            .map(|compressed| self.message_path(account, folder, id, compressed))
+            .find(|path| path.exists())
+    }
+
+    /// Point a message at a body file, replacing any previous one. Falls back
+    /// to a copy on filesystems without hard links.
+    fn link(&self, account: &str, folder: &str, id: &str, body: &Path) -> Result<()> {
+        for compressed in [true, false] {
+            let path = self.message_path(account, folder, id, compressed);
+            if path.exists() {
+                fs::remove_file(&path).with_context(|| format!("remove {}", path.display()))?;
+            }
+        }
+        let compressed = body.extension().and_then(|ext| ext.to_str()) == Some("zst");
+        let path = self.message_path(account, folder, id, compressed);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("create directory {}", parent.display()))?;
+        }
+        if fs::hard_link(body, &path).is_err() {
+            fs::copy(body, &path)
+                .with_context(|| format!("copy {} to {}", body.display(), path.display()))?;
+        }
+        Ok(())
+    }
+
+    fn search_path(&self, account: &str, folder: &str) -> PathBuf {
+        self.root
+            .join("search")
+            .join(encode_component(account))
+            .join(format!("{}.json", encode_component(folder)))
+    }
+}
+
+impl Store for FsStore {
+    fn accounts(&self) -> Result<Option<Vec<Account>>> {
+        read_json(&self.root.join("accounts.json"))
+    }
+
+    fn put_accounts(&self, accounts: &[Account]) -> Result<()> {
+        write_json(&self.root.join("accounts.json"), &accounts)
+    }
+
+    fn folders(&self, account: &str) -> Result<Option<Vec<Folder>>> {
+        read_json(&self.folders_path(account))
+    }
+
+    fn put_folders(&self, account: &str, folders: &[Folder]) -> Result<()> {
+        write_json(&self.folders_path(account), &folders)
+    }
+
+    fn account_names(&self) -> Result<Vec<String>> {
+        let mut names: Vec<String> = read_dir_entries(&self.root.join("envelopes"))?
+            .into_iter()
+            .filter(|entry| entry.path().is_dir())
+            .filter_map(|entry| decode_component(&entry.file_name().to_string_lossy()))
+            .collect();
+        names.sort();
+        Ok(names)
+    }
+
+    /// Folders are the index files under `envelopes/<account>`.
+    fn folder_names(&self, account: &str) -> Result<Vec<String>> {
+        let index_dir = self.root.join("envelopes").join(encode_component(account));
+        let mut names: Vec<String> = read_dir_entries(&index_dir)?
+            .into_iter()
+            .filter_map(|entry| {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                decode_component(name.strip_suffix(".json")?)
+            })
+            .collect();
+        names.sort();
+        Ok(names)
+    }
+
+    /// Read the folder index, building it from the meta files when the
+    /// folder has none yet.
+    fn envelopes(&self, account: &str, folder: &str) -> Result<Vec<Envelope>> {
+        if let Some(envelopes) = read_json(&self.index_path(account, folder))? {
+            return Ok(envelopes);
+        }
+        let mut envelopes = self.read_meta_dir(account, folder)?;
+        if !envelopes.is_empty() {
+            sort_by_date(&mut envelopes);
+            self.write_index(account, folder, &envelopes)?;
+        }
+        Ok(envelopes)
+    }
+
+    /// Read the meta file of an envelope, rebuilding the missing meta files
+    /// of the folder from its index first.
+    fn envelope(&self, account: &str, folder: &str, id: &str) -> Result<Option<Envelope>> {
+        if let Some(envelope) = read_json(&self.meta_path(account, folder, id))? {
+            return Ok(Some(envelope));
+        }
+        let envelopes = self.envelopes(account, folder)?;
+        if !envelopes.iter().any(|envelope| envelope.id == id) {
+            return Ok(None);
+        }
+        envelopes
+            .par_iter()
+            .filter(|envelope| !self.meta_path(account, folder, &envelope.id).exists())
+            .try_for_each(|envelope| {
+                write_json(&self.meta_path(account, folder, &envelope.id), envelope)
+            })?;
+        Ok(envelopes.into_iter().find(|envelope| envelope.id == id))
+    }
+
+    /// Write the folder index and the meta files of new or changed
+    /// envelopes, removing the meta files of envelopes no longer in the
+    /// folder.
+    fn put_envelopes(&self, account: &str, folder: &str, envelopes: &[Envelope]) -> Result<()> {
+        // An unreadable index only costs rewriting every meta file.
+        let previous: Vec<Envelope> = read_json(&self.index_path(account, folder))
+            .ok()
+            .flatten()
+            .unwrap_or_default();
+        let previous: HashMap<&str, &Envelope> = previous
+            .iter()
+            .map(|envelope| (envelope.id.as_str(), envelope))
+            .collect();
+        let mut envelopes = envelopes.to_vec();
+        sort_by_date(&mut envelopes);
+        self.write_index(account, folder, &envelopes)?;
+
+        envelopes
+            .par_iter()
+            .filter(|envelope| previous.get(envelope.id.as_str()) != Some(envelope))
+            .try_for_each(|envelope| {
+                write_json(&self.meta_path(account, folder, &envelope.id), envelope)
+            })?;
+
+        let current: HashSet<String> = envelopes
+            .iter()
+            .map(|envelope| format!("{}.json", encode_component(&envelope.id)))
+            .collect();
+        let meta_dir = self.meta_dir(account, folder);
+        if !meta_dir.exists() {
+            return Ok(());
+        }
+        for entry in
+            fs::read_dir(&meta_dir).with_context(|| format!("read {}", meta_dir.display()))?
+        {
+            let entry = entry.with_context(|| format!("read entry in {}", meta_dir.display()))?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if entry.path().is_file() && name.ends_with(".json") && !current.contains(&name) {
+                fs::remove_file(entry.path())
+                    .with_context(|| format!("remove {}", entry.path().display()))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn update_envelopes(&self, account: &str, folder: &str, updated: &[Envelope]) -> Result<()> {
+        let updates: HashMap<&str, &Envelope> = updated
+            .iter()
+            .map(|envelope| (envelope.id.as_str(), envelope))
+            .collect();
+        let mut envelopes = self.envelopes(account, folder)?;
+        for envelope in &mut envelopes {
+            if let Some(update) = updates.get(envelope.id.as_str()) {
+                *envelope = (*update).clone();
+            }
+        }
+        sort_by_date(&mut envelopes);
+        self.write_index(account, folder, &envelopes)?;
+        updated.par_iter().try_for_each(|envelope| {
+            write_json(&self.meta_path(account, folder, &envelope.id), envelope)
+        })
+    }
+
+    fn has_message(&self, account: &str, folder: &str, id: &str) -> Result<bool> {
+        Ok(self.existing_message_path(account, folder, id).is_some())
+    }
+
+    fn read_message(&self, account: &str, folder: &str, id: &str) -> Result<Vec<u8>> {
+        let path = self
+            .existing_message_path(account, folder, id)
+            .unwrap_or_else(|| self.message_path(account, folder, id, false));
+        let bytes = fs::read(&path).with_context(|| format!("read {}", path.display()))?;
+        decode(bytes).with_context(|| format!("read {}", path.display()))
+    }
+
+    /// Store the body as a blob, unless an identical one with the store
+    /// compression exists, and link the message to it.
+    fn put_message(&self, account: &str, folder: &str, id: &str, bytes: &[u8]) -> Result<()> {
+        let hash = content_hash(bytes);
+        let blob = self.blob_path(&hash, matches!(self.compression, Compression::Zstd(_)));
+        if !blob.exists() {
+            // Concurrent writers of the same blob each use their own
+            // temporary file; the rename makes the blob appear complete.
+            let temp = blob.with_extension(format!("{:?}.tmp", std::thread::current().id()));
+            write_bytes(&temp, &self.compression.encode(bytes)?)?;
+            fs::rename(&temp, &blob)
+                .with_context(|| format!("rename {} to {}", temp.display(), blob.display()))?;
+        }
+        self.link(account, folder, id, &blob)
+    }
+
+    fn delete_message(&self, account: &str, folder: &str, id: &str) -> Result<()> {
+        for compressed in [true, false] {
+            let path = self.message_path(account, folder, id, compressed);
+            if path.exists() {
+                fs::remove_file(&path).with_context(|| format!("remove {}", path.display()))?;
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    fn prune_blobs(&self) -> Result<usize> {
+        use std::os::unix::fs::MetadataExt;
+
+        let blobs_dir = self.root.join("blobs");
+        if !blobs_dir.exists() {
+            return Ok(0);
+        }
+        let mut pruned = 0;
+        for shard in
+            fs::read_dir(&blobs_dir).with_context(|| format!("read {}", blobs_dir.display()))?
+        {
+            let shard = shard.with_context(|| format!("read entry in {}", blobs_dir.display()))?;
+            let shard = shard.path();
+            for blob in fs::read_dir(&shard).with_context(|| format!("read {}", shard.display()))? {
+                let blob = blob.with_context(|| format!("read entry in {}", shard.display()))?;
+                let metadata = blob
+                    .metadata()
+                    .with_context(|| format!("read {}", blob.path().display()))?;
+                let temporary = blob.path().extension().is_some_and(|ext| ext == "tmp");
+                if metadata.is_file() && metadata.nlink() == 1 && !temporary {
+                    fs::remove_file(blob.path())
+                        .with_context(|| format!("remove {}", blob.path().display()))?;
+                    pruned += 1;
+                }
+            }
+        }
+        Ok(pruned)
+    }
+
+    /// Link counts are not available here, so blobs are kept.
+    #[cfg(not(unix))]
+    fn prune_blobs(&self) -> Result<usize> {
+        Ok(0)
+    }
+
+    fn search_index(&self, account: &str, folder: &str) -> Result<Option<Vec<u8>>> {
+        let path = self.search_path(account, folder);
+        if !path.exists() {
+            return Ok(None);
+        }
+        fs::read(&path)
+            .map(Some)
+            .with_context(|| format!("read {}", path.display()))
+    }
+
+    fn put_search_index(&self, account: &str, folder: &str, data: &[u8]) -> Result<()> {
+        write_bytes(&self.search_path(account, folder), data)
+    }
+}
+
+fn read_json<T: for<'de> Deserialize<'de>>(path: &Path) -> Result<Option<T>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let data = fs::read(path).with_context(|| format!("read {}", path.display()))?;
+    serde_json::from_slice(&data)
+        .map(Some)
+        .with_context(|| format!("parse {}", path.display()))
+}
+
+/// Encode a name as one path component. ASCII letters and digits, `-`, `_`,
+/// and `.` or spaces other than at either end are kept; every other byte is
+/// written `%XX`. Decoding with [`decode_component`] gives the name back.
+fn encode_component(name: &str) -> String {
+    let last = name.len().saturating_sub(1);
+    let mut encoded = String::with_capacity(name.len());
+    for (index, byte) in name.bytes().enumerate() {
+        let inner = index != 0 && index != last;
+        if byte.is_ascii_alphanumeric()
+            || byte == b'-'
+            || byte == b'_'
+            || (inner && (byte == b'.' || byte == b' '))
+        {
+            encoded.push(char::from(byte));
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    encoded
+}
+
+/// Name encoded by [`encode_component`], or `None` for a component that is
+/// not a valid encoding.
+fn decode_component(component: &str) -> Option<String> {
+    let bytes = component.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(index + 1..index + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            index += 3;
+        } else {
+            decoded.push(bytes[index]);
+            index += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+fn is_encoded(component: &str) -> bool {
+    decode_component(component).is_some_and(|name| encode_component(&name) == component)
+}
+
+/// Entries of a directory, or none when it does not exist.
+fn read_dir_entries(dir: &Path) -> Result<Vec<fs::DirEntry>> {
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    fs::read_dir(dir)
+        .with_context(|| format!("read {}", dir.display()))?
+        .map(|entry| entry.with_context(|| format!("read entry in {}", dir.display())))
+        .collect()
+}
+
+fn move_file(from: &Path, to: &Path) -> Result<()> {
+    if from == to || !from.is_file() {
+        return Ok(());
+    }
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("create directory {}", parent.display()))?;
+    }
+    fs::rename(from, to).with_context(|| format!("rename {} to {}", from.display(), to.display()))
+}
+
+/// Remove the empty directories below `dir`, keeping `dir` itself.
+fn remove_empty_dirs(dir: &Path) -> Result<()> {
+    for entry in read_dir_entries(dir)? {
+        let path = entry.path();
+        if path.is_dir() {
+            remove_empty_dirs(&path)?;
+            if read_dir_entries(&path)?.is_empty() {
+                fs::remove_dir(&path).with_context(|| format!("remove {}", path.display()))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn components_round_trip() {
+        for name in [
+            ".",
+            "..",
+            "/",
+            "Work/2024",
+            "[Gmail]/Sent Mail",
+            " leading",
+            "trailing ",
+            "in side",
+            "%41",
+            "Café",
+        ] {
+            let encoded = encode_component(name);
+            assert!(!encoded.contains('/'), "{encoded}");
+            assert!(encoded != "." && encoded != "..", "{encoded}");
+            assert_eq!(decode_component(&encoded).as_deref(), Some(name));
+            assert!(is_encoded(&encoded), "{encoded}");
+        }
+        assert_eq!(encode_component("."), "%2E");
+        assert_eq!(encode_component(".."), "%2E%2E");
+        assert_eq!(encode_component("a.b"), "a.b");
+        assert_eq!(encode_component("in side"), "in side");
+        assert_eq!(encode_component(" x "), "%20x%20");
+        assert_eq!(encode_component("%41"), "%2541");
+        assert!(!is_encoded("Work%41"));
+        assert_eq!(decode_component("%4"), None);
+    }
+
+    #[test]
+    fn encode_paths_keeps_raw_names() {
+        let root = std::env::temp_dir().join(format!(
+            "himalaya-cache-test-{}-encode-paths",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&root);
+        let write = |path: &str, data: &str| {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, data).unwrap();
+        };
+        // A version 1 cache: raw names, one of which looks encoded.
+        write(
+            "accounts.json",
+            r#"[{"name": "a", "backend": null, "default": true}]"#,
+        );
+        write(
+            "folders/a.json",
+            r#"[{"name": "Caf%C3%A9", "desc": null}, {"name": "Work/2024", "desc": null}]"#,
+        );
+        write("envelopes/a/Caf%C3%A9.json", r#"[{"id": "%41"}]"#);
+        write("envelopes/a/Work/2024.json", r#"[{"id": "2"}]"#);
+        write("messages/a/Caf%C3%A9/%41.eml", "Subject: one\r\n\r\n");
+        write("messages/a/Work/2024/2.eml", "Subject: two\r\n\r\n");
+
+        let store = FsStore::new(&root, Compression::None);
+        store.encode_paths().unwrap();
+        // Running it again, as after an interrupted upgrade, changes nothing.
+        store.encode_paths().unwrap();
+
+        assert_eq!(store.folder_names("a").unwrap(), ["Caf%C3%A9", "Work/2024"]);
+        assert!(store.folders("a").unwrap().is_some());
+        let ids: Vec<String> = store
+            .envelopes("a", "Caf%C3%A9")
+            .unwrap()
+            .into_iter()
+            .map(|envelope| envelope.id)
+            .collect();
+        assert_eq!(ids, ["%41"]);
+        assert!(store.has_message("a", "Caf%C3%A9", "%41").unwrap());
+        assert!(store.has_message("a", "Work/2024", "2").unwrap());
+        assert!(root.join("envelopes/a/Caf%25C3%25A9.json").is_file());
+        assert!(root.join("messages/a/Work%2F2024/2.eml").is_file());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn put_envelopes_writes_changed_meta_files() {
+        let root = std::env::temp_dir().join(format!(
+            "himalaya-cache-test-{}-meta-files",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&root);
+        let store = FsStore::new(&root, Compression::None);
+        let envelopes =
+            |value: serde_json::Value| -> Vec<Envelope> { serde_json::from_value(value).unwrap() };
+        let meta = |id: &str| fs::read_to_string(store.meta_path("a", "INBOX", id)).unwrap();
+
+        store
+            .put_envelopes(
+                "a",
+                "INBOX",
+                &envelopes(serde_json::json!([{"id": "1"}, {"id": "2"}, {"id": "3"}])),
+            )
+            .unwrap();
+        // Marked files show which ones the next sync rewrites.
+        for id in ["1", "2"] {
+            fs::write(store.meta_path("a", "INBOX", id), "untouched").unwrap();
+        }
+        store
+            .put_envelopes(
+                "a",
+                "INBOX",
+                &envelopes(serde_json::json!([
+                    {"id": "1"},
+                    {"id": "2", "subject": "changed"},
+                    {"id": "4"},
+                ])),
+            )
+            .unwrap();
+
+        assert_eq!(meta("1"), "untouched");
+        assert!(meta("2").contains("changed"));
+        assert!(meta("4").contains("\"4\""));
+        assert!(!store.meta_path("a", "INBOX", "3").exists());
+        assert_eq!(store.envelopes("a", "INBOX").unwrap().len(), 3);
+
+        store
+            .update_envelopes(
+                "a",
+                "INBOX",
+                &envelopes(serde_json::json!([{"id": "1", "subject": "updated"}])),
+            )
+            .unwrap();
+        let subjects: Vec<Option<String>> = ["1", "2", "4"]
+            .into_iter()
+            .map(|id| store.envelope("a", "INBOX", id).unwrap().unwrap().subject)
+            .collect();
+        assert_eq!(
+            subjects,
+            [
+                Some("updated".to_string()),
+                Some("changed".to_string()),
+                None
+            ]
+        );
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,155 @@
+//! Store wrapper keeping folder and search indices in memory, for
+//! long-lived processes such as `serve`. Writes go through to the wrapped
+//! store and drop the affected entries.
+
+use anyhow::Result;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use super::Store;
+use crate::{Account, Envelope, Folder, search::FolderIndex};
+
+type Key = (String, String);
+
+pub struct CachedStore {
+    inner: Box<dyn Store>,
+    envelopes: Mutex<HashMap<Key, Arc<Vec<Envelope>>>>,
+    search_indices: Mutex<HashMap<Key, Arc<FolderIndex>>>,
+}
+
+impl CachedStore {
+    pub fn new(inner: Box<dyn Store>) -> Self {
+        Self {
+            inner,
+            envelopes: Mutex::new(HashMap::new()),
+            search_indices: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn cached_envelopes(&self, account: &str, folder: &str) -> Result<Arc<Vec<Envelope>>> {
+        let key = (account.to_string(), folder.to_string());
+        if let Some(envelopes) = self
+            .envelopes
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&key)
+        {
+            return Ok(envelopes.clone());
+        }
+        let envelopes = Arc::new(self.inner.envelopes(account, folder)?);
+        self.envelopes
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(key, envelopes.clone());
+        Ok(envelopes)
+    }
+
+    fn forget_envelopes(&self, account: &str, folder: &str) {
+        let key = (account.to_string(), folder.to_string());
+        self.envelopes
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&key);
+    }
+}
+
+impl Store for CachedStore {
+    fn accounts(&self) -> Result<Option<Vec<Account>>> {
+        self.inner.accounts()
+    }
+
+    fn put_accounts(&self, accounts: &[Account]) -> Result<()> {
+        self.inner.put_accounts(accounts)
+    }
+
+    fn folders(&self, account: &str) -> Result<Option<Vec<Folder>>> {
+        self.inner.folders(account)
+    }
+
+    fn put_folders(&self, account: &str, folders: &[Folder]) -> Result<()> {
+        self.inner.put_folders(account, folders)
+    }
+
+    fn account_names(&self) -> Result<Vec<String>> {
+        self.inner.account_names()
+    }
+
+    fn folder_names(&self, account: &str) -> Result<Vec<String>> {
+        self.inner.folder_names(account)
+    }
+
+    fn envelopes(&self, account: &str, folder: &str) -> Result<Vec<Envelope>> {
+        Ok(self.cached_envelopes(account, folder)?.to_vec())
+    }
+
+    fn envelope(&self, account: &str, folder: &str, id: &str) -> Result<Option<Envelope>> {
+        Ok(self
+            .cached_envelopes(account, folder)?
+            .iter()
+            .find(|envelope| envelope.id == id)
+            .cloned())
+    }
+
+    fn put_envelopes(&self, account: &str, folder: &str, envelopes: &[Envelope]) -> Result<()> {
+        self.forget_envelopes(account, folder);
+        self.inner.put_envelopes(account, folder, envelopes)
+    }
+
+    fn update_envelopes(&self, account: &str, folder: &str, envelopes: &[Envelope]) -> Result<()> {
+        self.forget_envelopes(account, folder);
+        self.inner.update_envelopes(account, folder, envelopes)
+    }
+
+    fn has_message(&self, account: &str, folder: &str, id: &str) -> Result<bool> {
+        self.inner.has_message(account, folder, id)
+    }
+
+    fn read_message(&self, account: &str, folder: &str, id: &str) -> Result<Vec<u8>> {
+        self.inner.read_message(account, folder, id)
+    }
+
+    fn put_message(&self, account: &str, folder: &str, id: &str, bytes: &[u8]) -> Result<()> {
+        self.inner.put_message(account, folder, id, bytes)
+    }
+
+    fn delete_message(&self, account: &str, folder: &str, id: &str) -> Result<()> {
+        self.inner.delete_message(account, folder, id)
+    }
+
+    fn prune_blobs(&self) -> Result<usize> {
+        self.inner.prune_blobs()
+    }
+
+    fn search_index(&self, account: &str, folder: &str) -> Result<Option<Vec<u8>>> {
+        self.inner.search_index(account, folder)
+    }
+
+    fn put_search_index(&self, account: &str, folder: &str, data: &[u8]) -> Result<()> {
+        let key = (account.to_string(), folder.to_string());
+        self.search_indices
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&key);
+        self.inner.put_search_index(account, folder, data)
+    }
+
+    fn folder_index(&self, account: &str, folder: &str) -> Result<Arc<FolderIndex>> {
+        let key = (account.to_string(), folder.to_string());
+        if let Some(index) = self
+            .search_indices
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&key)
+        {
+            return Ok(index.clone());
+        }
+        let index = self.inner.folder_index(account, folder)?;
+        self.search_indices
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(key, index.clone());
+        Ok(index)
+    }
+}
@@ -0,0 +1,234 @@
+//! Versioned cache layout.
+//!
+//! The cache root holds a `version` file with the layout version. Caches
+//! without one predate versioning and are version 1. Opening a cache runs
+//! every migration between its version and [`CURRENT_VERSION`], recording
+//! the new version after each step; a cache written by a newer
+//! himalaya-cache is refused rather than misread. A new cache gets its
+//! version file with its first data, so opening one does not touch the disk.
+
+use anyhow::{Context, Result, bail};
+use serde::Serialize;
+use std::{fs, path::Path};
+
+use super::{Compression, FsStore, SQLITE_FILE, SqliteStore};
+
+/// Name of the layout version file inside the cache directory.
+pub const VERSION_FILE: &str = "version";
+
+/// Layout version written by this build.
+pub const CURRENT_VERSION: u32 = 3;
+
+/// One step of the layout history, upgrading version `to - 1` to `to`.
+#[derive(Serialize)]
+pub struct Migration {
+    pub from: u32,
+    pub to: u32,
+    pub description: &'static str,
+    #[serde(skip)]
+    run: fn(&Path) -> Result<()>,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        from: 1,
+        to: 2,
+        description: "encode account, folder and id path components",
+        run: encode_paths,
+    },
+    Migration {
+        from: 2,
+        to: 3,
+        description: "store SQLite message bodies as shared blobs",
+        run: share_sqlite_bodies,
+    },
+];
+
+/// Migrations the cache needs, oldest first.
+pub fn pending(cache_dir: &Path) -> Result<Vec<&'static Migration>> {
+    let version = version(cache_dir)?;
+    if version > CURRENT_VERSION {
+        bail!(
+            "cache {} has layout version {}, newer than version {} supported by this \
+             himalaya-cache; upgrade himalaya-cache to use it",
+            cache_dir.display(),
+            version,
+            CURRENT_VERSION
+        );
+    }
+    Ok(MIGRATIONS
+        .iter()
+        .filter(|migration| migration.from >= version)
+        .collect())
+}
+
+/// Bring the cache to the current layout, returning the migrations run.
+pub fn upgrade(cache_dir: &Path) -> Result<Vec<&'static Migration>> {
+    let migrations = pending(cache_dir)?;
+    for migration in &migrations {
+        (migration.run)(cache_dir).with_context(|| {
+            format!(
+                "migrate cache from version {} to {}: {}",
+                migration.from, migration.to, migration.description
+            )
+        })?;
+        write_version(cache_dir, migration.to)?;
+    }
+    Ok(migrations)
+}
+
+/// Record the current version in a cache without a version file, once data
+/// was written to it.
+pub fn record_version(cache_dir: &Path) -> Result<()> {
+    if cache_dir.join(VERSION_FILE).exists() {
+        return Ok(());
+    }
+    write_version(cache_dir, CURRENT_VERSION)
+}
+
+/// Layout version of the cache: the version file, 1 for a cache with data
+/// but no version file, and the current version for a new cache.
+fn version(cache_dir: &Path) -> Result<u32> {
+    let path = cache_dir.join(VERSION_FILE);
+    if path.exists() {
+        let data = fs::read_to_string(&path).with_context(|| format!("read {}", path.display()))?;
+        return data
+            .trim()
+            .parse()
+            .with_context(|| format!("parse {}", path.display()));
+    }
+    let has_data = [
+        "accounts.json",
+        "folders",
+        "envelopes",
+        "meta",
+        "messages",
+        SQLITE_FILE,
+    ]
+    .iter()
+    .any(|name| cache_dir.join(name).exists());
+    Ok(if has_data { 1 } else { CURRENT_VERSION })
+}
+
+fn write_version(cache_dir: &Path, version: u32) -> Result<()> {
+    fs::create_dir_all(cache_dir)
+        .with_context(|| format!("create cache dir {}", cache_dir.display()))?;
+    let path = cache_dir.join(VERSION_FILE);
+    fs::write(&path, format!("{version}\n")).with_context(|| format!("write {}", path.display()))
+}
+
+fn encode_paths(cache_dir: &Path) -> Result<()> {
+    FsStore::new(cache_dir, Compression::None).encode_paths()
+}
+
+fn share_sqlite_bodies(cache_dir: &Path) -> Result<()> {
+    let database = cache_dir.join(SQLITE_FILE);
+    if !database.exists() {
+        return Ok(());
+    }
+    SqliteStore::open(&database, Compression::None)?.share_message_bodies()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::open;
+    use std::path::PathBuf;
+
+    fn temp_cache(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("himalaya-cache-test-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn write(dir: &Path, path: &str, data: &str) {
+        let path = dir.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, data).unwrap();
+    }
+
+    fn versions(migrations: &[&Migration]) -> Vec<(u32, u32)> {
+        migrations
+            .iter()
+            .map(|migration| (migration.from, migration.to))
+            .collect()
+    }
+
+    #[test]
+    fn upgrades_version_1() {
+        let dir = temp_cache("migrate-v1");
+        write(&dir, "accounts.json", r#"[{"name": "a"}]"#);
+        write(&dir, "folders/a.json", r#"[{"name": "Work/2024"}]"#);
+        write(&dir, "envelopes/a/Work/2024.json", r#"[{"id": "1"}]"#);
+        write(&dir, "messages/a/Work/2024/1.eml", "Subject: one\r\n\r\n");
+
+        assert_eq!(versions(&pending(&dir).unwrap()), [(1, 2), (2, 3)]);
+        let store = open(&dir, Compression::None).unwrap();
+        assert_eq!(fs::read_to_string(dir.join(VERSION_FILE)).unwrap(), "3\n");
+        assert!(pending(&dir).unwrap().is_empty());
+        assert_eq!(store.folder_names("a").unwrap(), ["Work/2024"]);
+        assert_eq!(
+            store.read_message("a", "Work/2024", "1").unwrap(),
+            b"Subject: one\r\n\r\n"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn upgrades_version_2() {
+        let dir = temp_cache("migrate-v2");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(VERSION_FILE), "2\n").unwrap();
+        // Version 2 databases kept one body per message.
+        let connection = rusqlite::Connection::open(dir.join(SQLITE_FILE)).unwrap();
+        connection
+            .execute_batch(
+                "CREATE TABLE messages (account TEXT, folder TEXT, id TEXT, body BLOB);
+                 INSERT INTO messages VALUES ('a', 'INBOX', '1', CAST('Subject: one' AS BLOB));
+                 INSERT INTO messages VALUES ('a', 'INBOX', '2', CAST('Subject: one' AS BLOB));",
+            )
+            .unwrap();
+        drop(connection);
+
+        assert_eq!(versions(&upgrade(&dir).unwrap()), [(2, 3)]);
+        assert_eq!(fs::read_to_string(dir.join(VERSION_FILE)).unwrap(), "3\n");
+        let store = open(&dir, Compression::None).unwrap();
+        for id in ["1", "2"] {
+            assert_eq!(
+                store.read_message("a", "INBOX", id).unwrap(),
+                b"Subject: one"
+            );
+        }
+        let connection = rusqlite::Connection::open(dir.join(SQLITE_FILE)).unwrap();
+        let blobs: i64 = connection
+            .query_row("SELECT COUNT(*) FROM blobs", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(blobs, 1);
+        drop(connection);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_newer_version() {
+        let dir = temp_cache("migrate-newer");
+        write(&dir, VERSION_FILE, &format!("{}\n", CURRENT_VERSION + 1));
+        let error = open(&dir, Compression::None).err().unwrap();
+        assert!(error.to_string().contains("newer than version"), "{error}");
+        assert!(!dir.join("accounts.json").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn new_cache_is_left_untouched_until_written() {
+        let dir = temp_cache("migrate-new");
+        let store = open(&dir, Compression::None).unwrap();
+        assert!(store.accounts().unwrap().is_none());
+        assert!(!dir.exists());
+
+        store.put_accounts(&[]).unwrap();
+        crate::store::mark_changed(&dir).unwrap();
+        assert_eq!(fs::read_to_string(dir.join(VERSION_FILE)).unwrap(), "3\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
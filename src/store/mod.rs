@@ -0,0 +1,257 @@
+//! Storage backends for cached accounts, folders, envelopes and messages.
+//!
+//! The default backend keeps the historical directory layout (one JSON file
+//! per envelope, one `.eml` per message). The SQLite backend keeps the same
+//! data in a single `cache.sqlite` file and is used once that file exists;
+//! `himalaya-cache store migrate` moves a cache between the two.
+//!
+//! Message bodies may be stored zstd-compressed; both backends recognize
+//! compressed bodies on read, so a cache can mix compressed and plain ones.
+//!
+//! Bodies are content-addressed by the SHA-256 of the raw message: a message
+//! cached in several folders (Gmail labels) is stored once and every folder
+//! id refers to that blob.
+
+use anyhow::{Context, Result};
+use rayon::prelude::*;
+use sha2::{Digest, Sha256};
+use std::{path::Path, sync::Arc};
+
+use crate::{Account, Envelope, Folder, parse_envelope_date, search};
+
+mod fs;
+mod memory;
+pub mod migrations;
+mod sqlite;
+
+pub use fs::FsStore;
+pub use memory::CachedStore;
+pub use sqlite::SqliteStore;
+
+/// Name of the SQLite database file inside the cache directory.
+pub const SQLITE_FILE: &str = "cache.sqlite";
+
+/// File rewritten after a command changes the cache, so long-running
+/// servers know to drop what they keep in memory.
+pub const CHANGED_FILE: &str = "changed";
+
+/// Frame magic number starting every zstd-compressed body.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// How message bodies are written.
+#[derive(Debug, Clone, Copy)]
+pub enum Compression {
+    None,
+    /// zstd at the given level.
+    Zstd(i32),
+}
+
+impl Compression {
+    fn encode(self, bytes: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(bytes.to_vec()),
+            Compression::Zstd(level) => {
+                zstd::encode_all(bytes, level).context("compress message body")
+            }
+        }
+    }
+}
+
+/// Hex SHA-256 of a raw message, the key of its stored body.
+fn content_hash(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Decompress a stored body if it is zstd-compressed. Raw messages are text
+/// and never start with the zstd magic number.
+fn decode(bytes: Vec<u8>) -> Result<Vec<u8>> {
+    if bytes.starts_with(&ZSTD_MAGIC) {
+        zstd::decode_all(bytes.as_slice()).context("decompress message body")
+    } else {
+        Ok(bytes)
+    }
+}
+
+/// Storage for everything sync caches.
+pub trait Store: Send + Sync {
+    /// Account list from the last full sync, if any.
+    fn accounts(&self) -> Result<Option<Vec<Account>>>;
+    fn put_accounts(&self, accounts: &[Account]) -> Result<()>;
+
+    /// Folder list of an account from the last sync, if any.
+    fn folders(&self, account: &str) -> Result<Option<Vec<Folder>>>;
+    fn put_folders(&self, account: &str, folders: &[Folder]) -> Result<()>;
+
+    /// Names of the accounts with cached envelopes.
+    fn account_names(&self) -> Result<Vec<String>>;
+    /// Names of the folders of an account with cached envelopes.
+    fn folder_names(&self, account: &str) -> Result<Vec<String>>;
+
+    /// Every cached envelope of a folder, newest first.
+    fn envelopes(&self, account: &str, folder: &str) -> Result<Vec<Envelope>>;
+    fn envelope(&self, account: &str, folder: &str, id: &str) -> Result<Option<Envelope>>;
+    /// Replace the cached envelopes of a folder.
+    fn put_envelopes(&self, account: &str, folder: &str, envelopes: &[Envelope]) -> Result<()>;
+    /// Replace the cached copies of the given envelopes, matched by id, and
+    /// leave the rest of the folder alone.
+    fn update_envelopes(&self, account: &str, folder: &str, envelopes: &[Envelope]) -> Result<()>;
+
+    fn has_message(&self, account: &str, folder: &str, id: &str) -> Result<bool>;
+    /// Raw bytes of a cached message, decompressed.
+    fn read_message(&self, account: &str, folder: &str, id: &str) -> Result<Vec<u8>>;
+    fn put_message(&self, account: &str, folder: &str, id: &str, bytes: &[u8]) -> Result<()>;
+    /// Forget the body of a message; the body itself goes at the next
+    /// [`Store::prune_blobs`] once no other message refers to it.
+    fn delete_message(&self, account: &str, folder: &str, id: &str) -> Result<()>;
+    /// Delete bodies no message refers to any more, returning how many.
+    fn prune_blobs(&self) -> Result<usize>;
+
+    /// Serialized search index of a folder, if any.
+    fn search_index(&self, account: &str, folder: &str) -> Result<Option<Vec<u8>>>;
+    fn put_search_index(&self, account: &str, folder: &str, data: &[u8]) -> Result<()>;
+
+    /// Parsed search index of a folder, empty when none was built.
+    fn folder_index(&self, account: &str, folder: &str) -> Result<Arc<search::FolderIndex>> {
+        let data = self.search_index(account, folder)?;
+        search::FolderIndex::parse(data.as_deref(), account, folder).map(Arc::new)
+    }
+}
+
+/// Record that the cache of `cache_dir` changed. A new cache also gets its
+/// layout version here.
+pub fn mark_changed(cache_dir: &Path) -> Result<()> {
+    migrations::record_version(cache_dir)?;
+    let path = cache_dir.join(CHANGED_FILE);
+    let stamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    std::fs::write(&path, stamp.to_string()).with_context(|| format!("write {}", path.display()))
+}
+
+/// Stamp written by the last [`mark_changed`], if any.
+pub fn changed_stamp(cache_dir: &Path) -> Option<String> {
+    std::fs::read_to_string(cache_dir.join(CHANGED_FILE)).ok()
+}
+
+/// Sort envelopes newest first, keeping the order of envelopes without a
+/// parsable date.
+pub fn sort_by_date(envelopes: &mut [Envelope]) {
+    envelopes.sort_by_key(|envelope| std::cmp::Reverse(parse_envelope_date(envelope)));
+}
+
+/// Open the store of a cache directory: SQLite when `cache.sqlite` exists,
+/// the directory layout otherwise. New bodies are written with `compression`.
+/// Older cache layouts are migrated first.
+pub fn open(cache_dir: &Path, compression: Compression) -> Result<Box<dyn Store>> {
+    migrations::upgrade(cache_dir)?;
+    let database = cache_dir.join(SQLITE_FILE);
+    if database.exists() {
+        Ok(Box::new(SqliteStore::open(&database, compression)?))
+    } else {
+        Ok(Box::new(FsStore::new(cache_dir, compression)))
+    }
+}
+
+/// Rewrite every cached body with the compression of the store, sharing
+/// identical bodies and dropping unreferenced ones. Returns the number of
+/// messages rewritten.
+pub fn rewrite_messages(store: &dyn Store) -> Result<usize> {
+    let mut messages = 0;
+    for account in store.account_names()? {
+        for folder in store.folder_names(&account)? {
+            let envelopes = store.envelopes(&account, &folder)?;
+            messages += envelopes
+                .par_iter()
+                .map(|envelope| {
+                    if !store.has_message(&account, &folder, &envelope.id)? {
+                        return Ok(0);
+                    }
+                    let bytes = store.read_message(&account, &folder, &envelope.id)?;
+                    store.put_message(&account, &folder, &envelope.id, &bytes)?;
+                    Ok(1)
+                })
+                .sum::<Result<usize>>()?;
+        }
+    }
+    store.prune_blobs()?;
+    Ok(messages)
+}
+
+/// Copy every cached item from one store to another, returning the number of
+/// messages copied.
+pub fn copy(from: &dyn Store, to: &dyn Store) -> Result<usize> {
+    if let Some(accounts) = from.accounts()? {
+        to.put_accounts(&accounts)?;
+    }
+
+    let mut account_names = from.account_names()?;
+    for account in from.accounts()?.into_iter().flatten() {
+        if !account_names.contains(&account.name) {
+            account_names.push(account.name);
+        }
+    }
+
+    let mut messages = 0;
+    for account in &account_names {
+        if let Some(folders) = from.folders(account)? {
+            to.put_folders(account, &folders)?;
+        }
+        for folder in from.folder_names(account)? {
+            let envelopes = from.envelopes(account, &folder)?;
+            to.put_envelopes(account, &folder, &envelopes)?;
+            for envelope in &envelopes {
+                if !from.has_message(account, &folder, &envelope.id)? {
+                    continue;
+                }
+                let bytes = from
+                    .read_message(account, &folder, &envelope.id)
+                    .with_context(|| format!("copy message {account}/{folder}/{}", envelope.id))?;
+                to.put_message(account, &folder, &envelope.id, &bytes)?;
+                messages += 1;
+            }
+            if let Some(index) = from.search_index(account, &folder)? {
+                to.put_search_index(account, &folder, &index)?;
+            }
+        }
+    }
+    Ok(messages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_cache(name: &str) -> std::path::PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("himalaya-cache-test-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn backends_list_empty_folders() {
+        let fs_dir = temp_cache("names-fs");
+        let sqlite_dir = temp_cache("names-sqlite");
+        let backends: [Box<dyn Store>; 2] = [
+            Box::new(FsStore::new(&fs_dir, Compression::None)),
+            Box::new(SqliteStore::open(&sqlite_dir.join(SQLITE_FILE), Compression::None).unwrap()),
+        ];
+        let inbox: Vec<Envelope> =
+            serde_json::from_value(serde_json::json!([{"id": "1"}])).unwrap();
+        for store in &backends {
+            store.put_envelopes("a", "INBOX", &inbox).unwrap();
+            store.put_envelopes("a", "Archive", &[]).unwrap();
+            store.put_envelopes("b", "Drafts", &[]).unwrap();
+            assert_eq!(store.account_names().unwrap(), ["a", "b"]);
+            assert_eq!(store.folder_names("a").unwrap(), ["Archive", "INBOX"]);
+            assert_eq!(store.folder_names("b").unwrap(), ["Drafts"]);
+            assert!(store.envelopes("a", "Archive").unwrap().is_empty());
+        }
+        std::fs::remove_dir_all(&fs_dir).unwrap();
+        std::fs::remove_dir_all(&sqlite_dir).unwrap();
+    }
+}
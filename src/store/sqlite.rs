@@ -0,0 +1,432 @@
+//! Single-file SQLite backend.
+
+use anyhow::{Context, Result};
+use rusqlite::{Connection, OptionalExtension, params};
+use std::{path::Path, sync::Mutex};
+
+use super::{Compression, Store, ZSTD_MAGIC, content_hash, decode};
+use crate::{Account, Envelope, Folder, parse_envelope_date};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS accounts (
+    position INTEGER PRIMARY KEY,
+    data TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS folders (
+    account TEXT NOT NULL,
+    position INTEGER NOT NULL,
+    data TEXT NOT NULL,
+    PRIMARY KEY (account, position)
+);
+CREATE TABLE IF NOT EXISTS envelopes (
+    account TEXT NOT NULL,
+    folder TEXT NOT NULL,
+    id TEXT NOT NULL,
+    date INTEGER,
+    data TEXT NOT NULL,
+    PRIMARY KEY (account, folder, id)
+);
+CREATE INDEX IF NOT EXISTS envelopes_by_date ON envelopes (account, folder, date);
+CREATE TABLE IF NOT EXISTS cached_folders (
+    account TEXT NOT NULL,
+    folder TEXT NOT NULL,
+    PRIMARY KEY (account, folder)
+);
+CREATE TABLE IF NOT EXISTS blobs (
+    hash TEXT PRIMARY KEY,
+    body BLOB NOT NULL
+);
+CREATE TABLE IF NOT EXISTS message_blobs (
+    account TEXT NOT NULL,
+    folder TEXT NOT NULL,
+    id TEXT NOT NULL,
+    hash TEXT NOT NULL,
+    PRIMARY KEY (account, folder, id)
+);
+CREATE TABLE IF NOT EXISTS search_indices (
+    account TEXT NOT NULL,
+    folder TEXT NOT NULL,
+    data BLOB NOT NULL,
+    PRIMARY KEY (account, folder)
+);
+";
+
+pub struct SqliteStore {
+    connection: Mutex<Connection>,
+    compression: Compression,
+}
+
+impl SqliteStore {
+    /// Open (or create) the database and make sure the schema exists.
+    pub fn open(path: &Path, compression: Compression) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("create directory {}", parent.display()))?;
+        }
+        let connection =
+            Connection::open(path).with_context(|| format!("open {}", path.display()))?;
+        connection
+            .pragma_update(None, "journal_mode", "WAL")
+            .context("enable sqlite WAL")?;
+        connection
+            .pragma_update(None, "synchronous", "NORMAL")
+            .context("set sqlite synchronous mode")?;
+        connection
+            .execute_batch(SCHEMA)
+            .with_context(|| format!("create schema in {}", path.display()))?;
+        Ok(Self {
+            connection: Mutex::new(connection),
+            compression,
+        })
+    }
+
+    /// Delete a database file along with its WAL and shared-memory files.
+    pub fn remove(path: &Path) -> Result<()> {
+        for suffix in ["", "-wal", "-shm"] {
+            let mut file = path.as_os_str().to_owned();
+            file.push(suffix);
+            let file = Path::new(&file);
+            if file.exists() {
+                std::fs::remove_file(file).with_context(|| format!("remove {}", file.display()))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Move bodies from the `messages` table of older databases, which
+    /// stored one body per message, into shared blobs.
+    pub fn share_message_bodies(&self) -> Result<()> {
+        let mut connection = self
+            .connection
+            .lock()
+            .map_err(|_| anyhow::anyhow!("sqlite connection poisoned"))?;
+        share_message_bodies(&mut connection).context("move message bodies to blobs")
+    }
+
+    fn with<T>(&self, run: impl FnOnce(&mut Connection) -> rusqlite::Result<T>) -> Result<T> {
+        let mut connection = self
+            .connection
+            .lock()
+            .map_err(|_| anyhow::anyhow!("sqlite connection poisoned"))?;
+        run(&mut connection).context("query sqlite cache")
+    }
+}
+
+impl Store for SqliteStore {
+    fn accounts(&self) -> Result<Option<Vec<Account>>> {
+        let rows: Vec<String> = self.with(|connection| {
+            let mut statement =
+                connection.prepare("SELECT data FROM accounts ORDER BY position")?;
+            statement.query_map([], |row| row.get(0))?.collect()
+        })?;
+        if rows.is_empty() {
+            return Ok(None);
+        }
+        rows.iter()
+            .map(|data| serde_json::from_str(data).context("parse cached account"))
+            .collect::<Result<_>>()
+            .map(Some)
+    }
+
+    fn put_accounts(&self, accounts: &[Account]) -> Result<()> {
+        let rows = accounts
+            .iter()
+            .map(serde_json::to_string)
+            .collect::<serde_json::Result<Vec<_>>>()
+            .context("serialize accounts")?;
+        self.with(|connection| {
+            let transaction = connection.transaction()?;
+            transaction.execute("DELETE FROM accounts", [])?;
+            for (position, data) in rows.iter().enumerate() {
+                transaction.execute(
+                    "INSERT INTO accounts (position, data) VALUES (?1, ?2)",
+                    params![position as i64, data],
+                )?;
+            }
+            transaction.commit()
+        })
+    }
+
+    fn folders(&self, account: &str) -> Result<Option<Vec<Folder>>> {
+        let rows: Vec<String> = self.with(|connection| {
+            let mut statement = connection
+                .prepare("SELECT data FROM folders WHERE account = ?1 ORDER BY position")?;
+            statement.query_map([account], |row| row.get(0))?.collect()
+        })?;
+        if rows.is_empty() {
+            return Ok(None);
+        }
+        rows.iter()
+            .map(|data| serde_json::from_str(data).context("parse cached folder"))
+            .collect::<Result<_>>()
+            .map(Some)
+    }
+
+    fn put_folders(&self, account: &str, folders: &[Folder]) -> Result<()> {
+        let rows = folders
+            .iter()
+            .map(serde_json::to_string)
+            .collect::<serde_json::Result<Vec<_>>>()
+            .context("serialize folders")?;
+        self.with(|connection| {
+            let transaction = connection.transaction()?;
+            transaction.execute("DELETE FROM folders WHERE account = ?1", [account])?;
+            for (position, data) in rows.iter().enumerate() {
+                transaction.execute(
+                    "INSERT INTO folders (account, position, data) VALUES (?1, ?2, ?3)",
+                    params![account, position as i64, data],
+                )?;
+            }
+            transaction.commit()
+        })
+    }
+
+    /// Accounts with a cached folder. Databases written before
+    /// `cached_folders` existed only know them from their envelopes.
+    fn account_names(&self) -> Result<Vec<String>> {
+        self.with(|connection| {
+            let mut statement = connection.prepare(
+                "SELECT account FROM cached_folders UNION SELECT account FROM envelopes \
+                 ORDER BY account",
+            )?;
+            statement.query_map([], |row| row.get(0))?.collect()
+        })
+    }
+
+    /// Folders whose envelopes were cached, even when there were none.
+    fn folder_names(&self, account: &str) -> Result<Vec<String>> {
+        self.with(|connection| {
+            let mut statement = connection.prepare(
+                "SELECT folder FROM cached_folders WHERE account = ?1 \
+                 UNION SELECT folder FROM envelopes WHERE account = ?1 ORDER BY folder",
+            )?;
+            statement.query_map([account], |row| row.get(0))?.collect()
+        })
+    }
+
+    fn envelopes(&self, account: &str, folder: &str) -> Result<Vec<Envelope>> {
+        let rows: Vec<String> = self.with(|connection| {
+            let mut statement = connection.prepare(
+                "SELECT data FROM envelopes WHERE account = ?1 AND folder = ?2 ORDER BY date DESC",
+            )?;
+            statement
+                .query_map([account, folder], |row| row.get(0))?
+                .collect()
+        })?;
+        rows.iter()
+            .map(|data| serde_json::from_str(data).context("parse cached envelope"))
+            .collect()
+    }
+
+    fn envelope(&self, account: &str, folder: &str, id: &str) -> Result<Option<Envelope>> {
+        let row: Option<String> = self.with(|connection| {
+            connection
+                .query_row(
+                    "SELECT data FROM envelopes WHERE account = ?1 AND folder = ?2 AND id = ?3",
+                    [account, folder, id],
+                    |row| row.get(0),
+                )
+                .optional()
+        })?;
+        row.map(|data| serde_json::from_str(&data).context("parse cached envelope"))
+            .transpose()
+    }
+
+    fn put_envelopes(&self, account: &str, folder: &str, envelopes: &[Envelope]) -> Result<()> {
+        let rows = envelope_rows(envelopes)?;
+        self.with(|connection| {
+            let transaction = connection.transaction()?;
+            transaction.execute(
+                "DELETE FROM envelopes WHERE account = ?1 AND folder = ?2",
+                [account, folder],
+            )?;
+            transaction.execute(
+                "INSERT OR IGNORE INTO cached_folders (account, folder) VALUES (?1, ?2)",
+                [account, folder],
+            )?;
+            {
+                let mut statement = transaction.prepare(
+                    "INSERT INTO envelopes (account, folder, id, date, data) \
+                     VALUES (?1, ?2, ?3, ?4, ?5)",
+                )?;
+                for (id, date, data) in &rows {
+                    statement.execute(params![account, folder, id, date, data])?;
+                }
+            }
+            transaction.commit()
+        })
+    }
+
+    fn update_envelopes(&self, account: &str, folder: &str, envelopes: &[Envelope]) -> Result<()> {
+        let rows = envelope_rows(envelopes)?;
+        self.with(|connection| {
+            let transaction = connection.transaction()?;
+            {
+                let mut statement = transaction.prepare(
+                    "UPDATE envelopes SET date = ?4, data = ?5 \
+                     WHERE account = ?1 AND folder = ?2 AND id = ?3",
+                )?;
+                for (id, date, data) in &rows {
+                    statement.execute(params![account, folder, id, date, data])?;
+                }
+            }
+            transaction.commit()
+        })
+    }
+
+    fn has_message(&self, account: &str, folder: &str, id: &str) -> Result<bool> {
+        self.with(|connection| {
+            connection
+                .query_row(
+                    "SELECT 1 FROM message_blobs WHERE account = ?1 AND folder = ?2 AND id = ?3",
+                    [account, folder, id],
+                    |_| Ok(()),
+                )
+                .optional()
+                .map(|row| row.is_some())
+        })
+    }
+
+    fn read_message(&self, account: &str, folder: &str, id: &str) -> Result<Vec<u8>> {
+        let body = self
+            .with(|connection| {
+                connection
+                    .query_row(
+                        "SELECT body FROM message_blobs JOIN blobs USING (hash) \
+                         WHERE account = ?1 AND folder = ?2 AND id = ?3",
+                        [account, folder, id],
+                        |row| row.get(0),
+                    )
+                    .optional()
+            })?
+            .with_context(|| format!("message {id} of {account}/{folder} is not cached"))?;
+        decode(body)
+    }
+
+    /// Store the body as a blob, unless an identical one with the store
+    /// compression exists, and point the message at it.
+    fn put_message(&self, account: &str, folder: &str, id: &str, bytes: &[u8]) -> Result<()> {
+        let hash = content_hash(bytes);
+        let stored_magic: Option<Vec<u8>> = self.with(|connection| {
+            connection
+                .query_row(
+                    "SELECT substr(body, 1, 4) FROM blobs WHERE hash = ?1",
+                    [&hash],
+                    |row| row.get(0),
+                )
+                .optional()
+        })?;
+        let compressed = matches!(self.compression, Compression::Zstd(_));
+        let body = match stored_magic {
+            Some(magic) if (magic == ZSTD_MAGIC) == compressed => None,
+            _ => Some(self.compression.encode(bytes)?),
+        };
+        self.with(|connection| {
+            let transaction = connection.transaction()?;
+            if let Some(body) = &body {
+                transaction.execute(
+                    "INSERT OR REPLACE INTO blobs (hash, body) VALUES (?1, ?2)",
+                    params![hash, body],
+                )?;
+            }
+            transaction.execute(
+                "INSERT OR REPLACE INTO message_blobs (account, folder, id, hash) \
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![account, folder, id, hash],
+            )?;
+            transaction.commit()
+        })
+    }
+
+    fn delete_message(&self, account: &str, folder: &str, id: &str) -> Result<()> {
+        self.with(|connection| {
+            connection.execute(
+                "DELETE FROM message_blobs WHERE account = ?1 AND folder = ?2 AND id = ?3",
+                [account, folder, id],
+            )
+        })?;
+        Ok(())
+    }
+
+    fn prune_blobs(&self) -> Result<usize> {
+        self.with(|connection| {
+            connection.execute(
+                "DELETE FROM blobs WHERE hash NOT IN (SELECT hash FROM message_blobs)",
+                [],
+            )
+        })
+    }
+
+    fn search_index(&self, account: &str, folder: &str) -> Result<Option<Vec<u8>>> {
+        self.with(|connection| {
+            connection
+                .query_row(
+                    "SELECT data FROM search_indices WHERE account = ?1 AND folder = ?2",
+                    [account, folder],
+                    |row| row.get(0),
+                )
+                .optional()
+        })
+    }
+
+    fn put_search_index(&self, account: &str, folder: &str, data: &[u8]) -> Result<()> {
+        self.with(|connection| {
+            connection.execute(
+                "INSERT OR REPLACE INTO search_indices (account, folder, data) VALUES (?1, ?2, ?3)",
+                params![account, folder, data],
+            )
+        })
+        .map(|_| ())
+    }
+}
+
+/// Id, timestamp and serialized data of each envelope, as stored in the
+/// `envelopes` table.
+fn envelope_rows(envelopes: &[Envelope]) -> Result<Vec<(&str, Option<i64>, String)>> {
+    envelopes
+        .iter()
+        .map(|envelope| {
+            let date = parse_envelope_date(envelope).map(|date| date.timestamp());
+            serde_json::to_string(envelope).map(|data| (envelope.id.as_str(), date, data))
+        })
+        .collect::<serde_json::Result<Vec<_>>>()
+        .context("serialize envelopes")
+}
+
+fn share_message_bodies(connection: &mut Connection) -> Result<()> {
+    let exists: Option<i64> = connection
+        .query_row(
+            "SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'messages'",
+            [],
+            |row| row.get(0),
+        )
+        .optional()?;
+    if exists.is_none() {
+        return Ok(());
+    }
+    let transaction = connection.transaction()?;
+    {
+        let mut rows = transaction.prepare("SELECT account, folder, id, body FROM messages")?;
+        let mut insert_blob =
+            transaction.prepare("INSERT OR IGNORE INTO blobs (hash, body) VALUES (?1, ?2)")?;
+        let mut insert_message = transaction.prepare(
+            "INSERT OR REPLACE INTO message_blobs (account, folder, id, hash) \
+             VALUES (?1, ?2, ?3, ?4)",
+        )?;
+        let mut rows = rows.query([])?;
+        while let Some(row) = rows.next()? {
+            let body: Vec<u8> = row.get(3)?;
+            let hash = content_hash(&decode(body.clone())?);
+            insert_blob.execute(params![hash, body])?;
+            insert_message.execute(params![
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                hash
+            ])?;
+        }
+    }
+    transaction.execute("DROP TABLE messages", [])?;
+    transaction.commit()?;
+    Ok(())
+}
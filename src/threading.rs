@@ -0,0 +1,358 @@
+//! Conversation threading of cached envelopes.
+//!
+//! Threads are built from the Message-ID, In-Reply-To and References headers
+//! of the cached messages, following the JWZ algorithm: reference chains link
+//! messages to their parents, and replies left without a cached parent are
+//! grouped with the original message of the same base subject.
+
+use serde::Serialize;
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+};
+
+use crate::{Envelope, mime::ThreadHeaders, parse_envelope_date};
+
+/// Cached envelope with the folder it lives in and its threading headers.
+pub struct ThreadMessage {
+    pub folder: String,
+    pub envelope: Envelope,
+    pub headers: ThreadHeaders,
+}
+
+/// Node of a thread tree, serialized as the envelope with extra fields.
+#[derive(Serialize)]
+pub struct ThreadNode<'a> {
+    #[serde(flatten)]
+    pub envelope: &'a Envelope,
+    pub folder: &'a str,
+    pub children: Vec<ThreadNode<'a>>,
+    #[serde(skip)]
+    index: usize,
+}
+
+#[derive(Default)]
+struct Container {
+    message: Option<usize>,
+    parent: Option<String>,
+    children: Vec<String>,
+}
+
+/// Build thread trees from the given messages. Only threads containing a
+/// message accepted by `seed` are returned, newest activity first. When the
+/// same Message-ID appears in several folders, the first message wins.
+pub fn build<'a>(
+    messages: &'a [ThreadMessage],
+    seed: &dyn Fn(&ThreadMessage) -> bool,
+) -> Vec<ThreadNode<'a>> {
+    let mut containers: HashMap<String, Container> = HashMap::new();
+
+    for (index, message) in messages.iter().enumerate() {
+        let key = match &message.headers.message_id {
+            Some(id)
+                if containers
+                    .get(id)
+                    .is_some_and(|container| container.message.is_some()) =>
+            {
+                continue;
+            }
+            Some(id) => id.clone(),
+            None => format!("{}/{}", message.folder, message.envelope.id),
+        };
+        containers.entry(key.clone()).or_default().message = Some(index);
+
+        let mut references = message.headers.references.clone();
+        if let Some(parent) = message.headers.in_reply_to.last()
+            && references.last() != Some(parent)
+        {
+            references.push(parent.clone());
+        }
+        for pair in references.windows(2) {
+            link(&mut containers, &pair[0], &pair[1]);
+        }
+        if let Some(parent) = references.last() {
+            link(&mut containers, parent, &key);
+        }
+    }
+
+    let mut roots: Vec<String> = containers
+        .iter()
+        .filter(|(_, container)| container.parent.is_none())
+        .map(|(key, _)| key.clone())
+        .collect();
+    roots.sort();
+    let roots = promote_empty_roots(&containers, roots);
+    let roots = group_by_subject(messages, &mut containers, roots);
+
+    let mut threads: Vec<(Option<i64>, ThreadNode)> = roots
+        .iter()
+        .filter_map(|root| to_node(messages, &containers, root))
+        .filter(|node| contains_seed(messages, node, seed))
+        .map(|node| (latest_timestamp(&node), node))
+        .collect();
+    threads.sort_by_key(|(latest, _)| Reverse(*latest));
+    threads.into_iter().map(|(_, node)| node).collect()
+}
+
+/// Make `child` a child of `parent`, unless it already has a parent or the
+/// link would create a loop.
+fn link(containers: &mut HashMap<String, Container>, parent: &str, child: &str) {
+    if parent == child {
+        return;
+    }
+    containers.entry(parent.to_string()).or_default();
+    containers.entry(child.to_string()).or_default();
+    if containers[child].parent.is_some() || is_ancestor(containers, child, parent) {
+        return;
+    }
+    if let Some(container) = containers.get_mut(child) {
+        container.parent = Some(parent.to_string());
+    }
+    if let Some(container) = containers.get_mut(parent) {
+        container.children.push(child.to_string());
+    }
+}
+
+fn is_ancestor(containers: &HashMap<String, Container>, ancestor: &str, key: &str) -> bool {
+    let mut seen = HashSet::new();
+    let mut current = Some(key.to_string());
+    while let Some(key) = current {
+        if key == ancestor || !seen.insert(key.clone()) {
+            return true;
+        }
+        current = containers
+            .get(&key)
+            .and_then(|container| container.parent.clone());
+    }
+    false
+}
+
+/// Replace roots that only stand for referenced-but-missing messages with
+/// their children.
+fn promote_empty_roots(containers: &HashMap<String, Container>, roots: Vec<String>) -> Vec<String> {
+    let mut promoted = Vec::new();
+    let mut pending = roots;
+    while let Some(root) = pending.pop() {
+        let container = &containers[&root];
+        if container.message.is_some() {
+            promoted.push(root);
+        } else {
+            pending.extend(container.children.iter().cloned());
+        }
+    }
+    promoted
+}
+
+/// Merge roots sharing a base subject. Only replies (`Re:`, `Fwd:`, ...) are
+/// folded, under the latest original sent before them (or the earliest root
+/// if all are replies); two originals are never merged, as a new message may
+/// reuse the subject of an unrelated older one.
+fn group_by_subject(
+    messages: &[ThreadMessage],
+    containers: &mut HashMap<String, Container>,
+    roots: Vec<String>,
+) -> Vec<String> {
+    let subject = |index: usize| {
+        messages[index]
+            .envelope
+            .subject
+            .as_deref()
+            .unwrap_or_default()
+    };
+    let date = |index: usize| parse_envelope_date(&messages[index].envelope);
+
+    let mut groups: HashMap<String, Vec<(String, usize)>> = HashMap::new();
+    let mut ungrouped = Vec::new();
+    for root in roots {
+        let index = containers[&root].message.unwrap_or_default();
+        let base = base_subject(subject(index));
+        if base.is_empty() {
+            ungrouped.push(root);
+        } else {
+            groups.entry(base).or_default().push((root, index));
+        }
+    }
+
+    for (_, mut group) in groups {
+        group.sort_by_key(|(_, index)| date(*index));
+        let (mut originals, mut replies): (Vec<_>, Vec<_>) = group
+            .into_iter()
+            .partition(|(_, index)| !is_reply(subject(*index)));
+        if originals.is_empty() {
+            originals.push(replies.remove(0));
+        }
+        for (child, index) in replies {
+            let (parent, _) = originals
+                .iter()
+                .rev()
+                .find(|(_, original)| date(*original) <= date(index))
+                .unwrap_or(&originals[0]);
+            if let Some(container) = containers.get_mut(&child) {
+                container.parent = Some(parent.clone());
+            }
+            if let Some(container) = containers.get_mut(parent) {
+                container.children.push(child);
+            }
+        }
+        ungrouped.extend(originals.into_iter().map(|(root, _)| root));
+    }
+    ungrouped
+}
+
+fn to_node<'a>(
+    messages: &'a [ThreadMessage],
+    containers: &HashMap<String, Container>,
+    key: &str,
+) -> Option<ThreadNode<'a>> {
+    let container = containers.get(key)?;
+    let index = container.message?;
+    let mut children = child_nodes(messages, containers, container);
+    children.sort_by_key(|node| parse_envelope_date(node.envelope));
+    Some(ThreadNode {
+        envelope: &messages[index].envelope,
+        folder: &messages[index].folder,
+        children,
+        index,
+    })
+}
+
+/// Child nodes of a container; replies to messages missing from the cache are
+/// attached to the nearest cached ancestor.
+fn child_nodes<'a>(
+    messages: &'a [ThreadMessage],
+    containers: &HashMap<String, Container>,
+    container: &Container,
+) -> Vec<ThreadNode<'a>> {
+    let mut nodes = Vec::new();
+    for key in &container.children {
+        let Some(child) = containers.get(key) else {
+            continue;
+        };
+        match to_node(messages, containers, key) {
+            Some(node) => nodes.push(node),
+            None => nodes.extend(child_nodes(messages, containers, child)),
+        }
+    }
+    nodes
+}
+
+fn contains_seed(
+    messages: &[ThreadMessage],
+    node: &ThreadNode,
+    seed: &dyn Fn(&ThreadMessage) -> bool,
+) -> bool {
+    seed(&messages[node.index])
+        || node
+            .children
+            .iter()
+            .any(|child| contains_seed(messages, child, seed))
+}
+
+fn latest_timestamp(node: &ThreadNode) -> Option<i64> {
+    let own = parse_envelope_date(node.envelope).map(|date| date.timestamp());
+    node.children
+        .iter()
+        .map(latest_timestamp)
+        .fold(own, |latest, child| latest.max(child))
+}
+
+fn is_reply(subject: &str) -> bool {
+    base_subject(subject) != subject.trim().to_lowercase()
+}
+
+/// Subject without reply and forward prefixes, lowercased.
+fn base_subject(subject: &str) -> String {
+    let mut subject = subject.trim().to_lowercase();
+    loop {
+        let stripped = ["re:", "fwd:", "fw:", "aw:", "wg:"]
+            .iter()
+            .find_map(|prefix| subject.strip_prefix(prefix))
+            .map(|rest| rest.trim_start().to_string());
+        match stripped {
+            Some(rest) => subject = rest,
+            None => return subject,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: &str, subject: &str, date: &str, references: &[&str]) -> ThreadMessage {
+        ThreadMessage {
+            folder: "INBOX".to_string(),
+            envelope: serde_json::from_value(serde_json::json!({
+                "id": id,
+                "subject": subject,
+                "date": date,
+            }))
+            .unwrap(),
+            headers: ThreadHeaders {
+                message_id: Some(format!("<{id}@example.org>")),
+                in_reply_to: references
+                    .last()
+                    .map(|parent| format!("<{parent}@example.org>"))
+                    .into_iter()
+                    .collect(),
+                references: references
+                    .iter()
+                    .map(|id| format!("<{id}@example.org>"))
+                    .collect(),
+            },
+        }
+    }
+
+    /// Threads as nested ids, e.g. `1(2(3))`.
+    fn shape(messages: &[ThreadMessage]) -> Vec<String> {
+        fn render(node: &ThreadNode) -> String {
+            if node.children.is_empty() {
+                return node.envelope.id.clone();
+            }
+            let children: Vec<String> = node.children.iter().map(render).collect();
+            format!("{}({})", node.envelope.id, children.join(" "))
+        }
+        build(messages, &|_| true).iter().map(render).collect()
+    }
+
+    #[test]
+    fn reference_chains_nest() {
+        let messages = [
+            message("3", "Re: Plan", "2024-01-03 10:00+00:00", &["1", "2"]),
+            message("1", "Plan", "2024-01-01 10:00+00:00", &[]),
+            message("2", "Re: Plan", "2024-01-02 10:00+00:00", &["1"]),
+            message("4", "Lunch", "2024-01-04 10:00+00:00", &[]),
+        ];
+        assert_eq!(shape(&messages), ["4", "1(2(3))"]);
+    }
+
+    #[test]
+    fn missing_parents_keep_their_replies_together() {
+        // 1 is not cached; 3 only references it through 2.
+        let messages = [
+            message("2", "Re: Plan", "2024-01-02 10:00+00:00", &["1"]),
+            message("3", "Re: Plan", "2024-01-03 10:00+00:00", &["1", "2"]),
+        ];
+        assert_eq!(shape(&messages), ["2(3)"]);
+    }
+
+    #[test]
+    fn replies_without_references_join_their_subject() {
+        let messages = [
+            message("1", "Weekly report", "2024-01-01 10:00+00:00", &[]),
+            message("2", "RE: weekly report", "2024-01-02 10:00+00:00", &[]),
+        ];
+        assert_eq!(shape(&messages), ["1(2)"]);
+    }
+
+    #[test]
+    fn originals_sharing_a_subject_stay_apart() {
+        let messages = [
+            message("1", "Weekly report", "2024-01-01 10:00+00:00", &[]),
+            message("2", "Weekly report", "2024-01-08 10:00+00:00", &[]),
+            message("3", "Re: Weekly report", "2024-01-09 10:00+00:00", &[]),
+        ];
+        // The reply goes with the report sent just before it.
+        assert_eq!(shape(&messages), ["2(3)", "1"]);
+    }
+}